@@ -0,0 +1,284 @@
+use crate::huffman::{compress_stream, decompress_stream, mode, mtime, set_mode, set_mtime, Dictionary, Options, Report};
+
+use log::info;
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// First bytes of an archive, followed by the format version and flags
+pub const MAGIC: &[u8; 4] = b"HUFA";
+const VERSION: u8 = 1;
+/// Archive flag: the entries are coded with the dictionary stored after the header
+const FLAG_SHARED: u8 = 1;
+/// Size of the trailer: offset of the central directory
+const TRAILER_SIZE: u64 = 8;
+
+/// A file of an archive, as listed in the central directory
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative path, components separated by /
+    pub path: String,
+    /// Uncompressed size
+    pub size: u64,
+    /// Offset of the compressed data from the start of the archive
+    pub offset: u64,
+    /// Size of the compressed data
+    pub compressed: u64,
+    /// Unix permission bits
+    pub mode: u32,
+    /// Modification time, seconds since the Unix epoch
+    pub mtime: i64,
+    /// CRC-32 of the uncompressed data
+    pub checksum: u32,
+}
+
+/// Archive layout: header (magic, version, flags, shared dictionary), then the data of every
+/// entry as a compressed file of its own, the central directory and the trailer.
+/// Adding entries overwrites the directory with their data, then writes it again after them.
+pub struct Archive {
+    file: File,
+    dictionary: Option<Dictionary>,
+    entries: Vec<Entry>,
+    // End of the entry data, where the central directory starts
+    end: u64,
+}
+
+impl Archive {
+    /// Creates an empty archive, its entries all coded with the dictionary if one is given
+    pub fn create(path: &str, dictionary: Option<Dictionary>) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION, if dictionary.is_some() { FLAG_SHARED } else { 0 }])?;
+        if let Some(dictionary) = &dictionary {
+            dictionary.write(&mut file)?;
+        }
+        let end = file.stream_position()?;
+        let mut archive = Archive { file, dictionary, entries: Vec::new(), end };
+        archive.write_directory()?;
+        Ok(archive)
+    }
+
+    /// Opens an archive and reads its central directory, `write` to add entries
+    pub fn open(path: &str, write: bool) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(write).open(path)?;
+        let mut header = [0u8; 6];
+        file.read_exact(&mut header).map_err(|_| "[-] Not an archive.")?;
+        if &header[..4] != MAGIC {
+            return Err(From::from("[-] Not an archive."));
+        }
+        if header[4] != VERSION {
+            return Err(From::from("[-] Unsupported archive version."));
+        }
+        let dictionary = match header[5] & FLAG_SHARED != 0 {
+            true => Some(Dictionary::read(&mut file)?),
+            false => None,
+        };
+        let start = file.stream_position()?;
+
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < start + TRAILER_SIZE + 4 {
+            return Err(From::from("[-] Truncated archive."));
+        }
+        file.seek(SeekFrom::End(-(TRAILER_SIZE as i64)))?;
+        let end = read_u64(&mut file)?;
+        if end < start || end > size - TRAILER_SIZE - 4 {
+            return Err(From::from("[-] Invalid central directory offset."));
+        }
+        file.seek(SeekFrom::Start(end))?;
+        let mut directory = BufReader::new((&file).take(size - TRAILER_SIZE - end));
+        let count = read_u32(&mut directory)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let entry = read_entry(&mut directory)?;
+            if entry.offset < start || entry.offset.checked_add(entry.compressed).is_none_or(|e| e > end) {
+                return Err(From::from(format!("[-] Entry [{}] lies outside of the data.", entry.path)));
+            }
+            entries.push(entry);
+        }
+        Ok(Archive { file, dictionary, entries, end })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Dictionary shared by the entries, None when each block has its own tables
+    pub fn dictionary(&self) -> Option<&Dictionary> {
+        self.dictionary.as_ref()
+    }
+
+    /// Compresses a file into the archive under `name`, replacing the entry of the same name.
+    /// The data of a replaced entry stays in the archive, unreferenced.
+    pub fn add(&mut self, path_in: &Path, name: &str, options: &Options) -> Result<&Entry, Box<dyn Error>> {
+        entry_path(Path::new(""), name)?;
+        let metadata = std::fs::metadata(path_in)?;
+        let options = Options { dictionary: self.dictionary.clone(), ..options.clone() };
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        let file_in = BufReader::new(File::open(path_in)?);
+        let report = match compress_stream(file_in, BufWriter::new(&self.file), name, &options) {
+            Ok(report) => report,
+            Err(e) => {
+                // The data overwrote the directory
+                self.write_directory()?;
+                return Err(e);
+            },
+        };
+        let entry = Entry {
+            path: String::from(name),
+            size: report.size_in,
+            offset: self.end,
+            compressed: report.size_out,
+            mode: mode(&metadata),
+            mtime: mtime(&metadata),
+            checksum: report.checksum,
+        };
+        self.end += report.size_out;
+        self.entries.retain(|e| e.path != name);
+        self.entries.push(entry);
+        self.write_directory()?;
+        info!("Added [{}] to the archive.", name);
+        Ok(self.entries.last().unwrap())
+    }
+
+    /// Writes the central directory and the trailer after the entry data
+    fn write_directory(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut directory = Vec::new();
+        directory.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            directory.extend_from_slice(&(entry.path.len() as u16).to_le_bytes());
+            directory.extend_from_slice(entry.path.as_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed.to_le_bytes());
+            directory.extend_from_slice(&entry.mode.to_le_bytes());
+            directory.extend_from_slice(&entry.mtime.to_le_bytes());
+            directory.extend_from_slice(&entry.checksum.to_le_bytes());
+        }
+        directory.extend_from_slice(&self.end.to_le_bytes());
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&directory)?;
+        self.file.set_len(self.end + directory.len() as u64)?;
+        Ok(())
+    }
+
+    /// Decompresses an entry to any writer, checking its size and checksum
+    pub fn extract_to<W: Write>(&self, entry: &Entry, writer: W, options: &Options) -> Result<Report, Box<dyn Error>> {
+        let options = Options { dictionary: self.dictionary.clone(), ..options.clone() };
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let report = decompress_stream(BufReader::new(file.take(entry.compressed)), writer, &entry.path, &options)?;
+        if report.size_out != entry.size || report.checksum != entry.checksum {
+            return Err(From::from(format!("[-] [{}] does not match its size or checksum.", entry.path)));
+        }
+        Ok(report)
+    }
+
+    /// Extracts an entry under the directory, restoring its permissions and modification time
+    pub fn extract(&self, entry: &Entry, dir: &Path, options: &Options) -> Result<PathBuf, Box<dyn Error>> {
+        let path = entry_path(dir, &entry.path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        if let Err(e) = self.extract_to(entry, BufWriter::new(&file), options) {
+            drop(file);
+            std::fs::remove_file(&path)?;
+            return Err(e);
+        }
+        drop(file);
+        set_mtime(&path, entry.mtime)?;
+        set_mode(&path, entry.mode)?;
+        Ok(path)
+    }
+}
+
+/// Path of an entry under `dir`, refusing names that would escape it
+fn entry_path(dir: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let mut ret = dir.to_path_buf();
+    for part in name.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(From::from(format!("[-] Entry [{}] points outside of the archive.", name))),
+            part if part.contains('\\') || part.contains(':') => return Err(From::from(format!("[-] Invalid entry name [{}].", name))),
+            part => ret.push(part),
+        }
+    }
+    if ret == dir {
+        return Err(From::from(format!("[-] Invalid entry name [{}].", name)));
+    }
+    Ok(ret)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Box<dyn Error>> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Box<dyn Error>> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<Entry, Box<dyn Error>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len)?;
+    let mut path = vec![0u8; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut path)?;
+    Ok(Entry {
+        path: String::from_utf8(path).map_err(|_| "[-] Entry name is not valid UTF-8.")?,
+        size: read_u64(reader)?,
+        offset: read_u64(reader)?,
+        compressed: read_u64(reader)?,
+        mode: read_u32(reader)?,
+        mtime: read_u64(reader)? as i64,
+        checksum: read_u32(reader)?,
+    })
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::huffman::tests::TempPath;
+
+    #[test]
+    fn check_archive() -> Result<(), Box<dyn Error>> {
+        let extracted = TempPath::new("extracted");
+        let dir: &Path = extracted.as_ref();
+        let path = TempPath::new("archive.hufa");
+        let files = ["./data/lazy_dog.txt", "./data/tara.txt"];
+        for shared in [false, true] {
+            let dictionary = match shared {
+                true => Some(crate::huffman::train_dictionary(&files)?),
+                false => None,
+            };
+            let mut archive = Archive::create(&path, dictionary)?;
+            archive.add(Path::new(files[0]), "text/lazy_dog.txt", &Options::default())?;
+            archive.add(Path::new(files[1]), "tara.txt", &Options::default())?;
+            // Replaced, not listed twice
+            archive.add(Path::new(files[1]), "tara.txt", &Options::default())?;
+            drop(archive);
+
+            let archive = Archive::open(&path, false)?;
+            assert_eq!(archive.dictionary().is_some(), shared);
+            let names: Vec<&str> = archive.entries().iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(names, ["text/lazy_dog.txt", "tara.txt"]);
+            for (entry, file) in archive.entries().iter().zip(files.iter()) {
+                let extracted = archive.extract(entry, dir, &Options::default())?;
+                assert_eq!(std::fs::read(&extracted)?, std::fs::read(file)?);
+                assert_eq!(mtime(&std::fs::metadata(&extracted)?), mtime(&std::fs::metadata(file)?));
+            }
+        }
+
+        assert!(entry_path(dir, "../escape").is_err());
+        assert_eq!(entry_path(dir, "/a/./b")?, dir.join("a").join("b"));
+        assert!(Archive::open("./data/tara.txt", false).is_err());
+
+        Ok(())
+    }
+}
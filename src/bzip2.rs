@@ -0,0 +1,325 @@
+use crate::canonical::CanonicalCode;
+use crate::file_bin::BitRead;
+use crate::transform;
+
+use log::warn;
+
+use std::error::Error;
+use std::io::Write;
+
+/// First bytes of a bzip2 stream, followed by the block size digit
+pub const MAGIC: &[u8; 3] = b"BZh";
+/// 48 bits starting a block (BCD pi)
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+/// 48 bits ending a stream (BCD sqrt(pi))
+const END_MAGIC: u64 = 0x1772_4538_5090;
+/// Symbols coded with each selected table
+const GROUP_SIZE: usize = 50;
+/// Selectors over this count are read but ignored, like bzip2 1.0.8 does
+const MAX_SELECTORS: usize = 18002;
+
+/// CRC-32 as computed by bzip2: polynomial 0x04C11DB7, most significant bit first
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= (b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Counts the bits read, to find the byte boundaries between concatenated streams
+struct Counted<'a, R: BitRead> {
+    reader: &'a mut R,
+    bits: u64,
+}
+
+impl<R: BitRead> BitRead for Counted<'_, R> {
+    fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.bits += 1;
+        self.reader.read_bit()
+    }
+}
+
+impl<R: BitRead> Counted<'_, R> {
+    fn read_u48(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(((self.read_bits(24)? as u64) << 24) | self.read_bits(24)? as u64)
+    }
+}
+
+/// True when the end of the input was reached
+fn is_eof(err: &(dyn Error + 'static)) -> bool {
+    match err.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+        None => false,
+    }
+}
+
+/// Reads a stream header, returns its block size digit, None when the header is not valid
+fn read_header<R: BitRead>(reader: &mut Counted<R>) -> Result<Option<u8>, Box<dyn Error>> {
+    let mut header = [0u8; 4];
+    for b in header.iter_mut() {
+        *b = reader.read_bits(8)? as u8;
+    }
+    match (&header[..3] == MAGIC, header[3]) {
+        (true, level @ b'1'..=b'9') => Ok(Some(level - b'0')),
+        _ => Ok(None),
+    }
+}
+
+/// Decompresses one or more concatenated bzip2 streams, returns the number of bytes written.
+/// Data after the streams that does not start another one is ignored, like bzip2 1.0.8 does.
+pub fn decompress<R: BitRead, W: Write>(reader: &mut R, out: &mut W) -> Result<usize, Box<dyn Error>> {
+    let mut reader = Counted { reader, bits: 0 };
+    let level = read_header(&mut reader)?.ok_or("[-] Not a bzip2 stream.")?;
+    let mut total = decompress_stream(&mut reader, out, level as usize * 100_000)?;
+
+    loop {
+        // Streams start on a byte boundary
+        let skip = ((8 - reader.bits % 8) % 8) as u8;
+        reader.read_bits(skip)?;
+        let start = reader.bits;
+        let level = match read_header(&mut reader) {
+            Ok(Some(level)) => level,
+            Err(ref e) if is_eof(e.as_ref()) && reader.bits == start + 1 => break,
+            Err(e) if !is_eof(e.as_ref()) => return Err(e),
+            _ => {
+                warn!("Trailing garbage after the bzip2 data ignored.");
+                break;
+            },
+        };
+        total += decompress_stream(&mut reader, out, level as usize * 100_000)?;
+    }
+
+    Ok(total)
+}
+
+/// Decompresses the blocks of a stream, after its header
+fn decompress_stream<R: BitRead, W: Write>(reader: &mut Counted<R>, out: &mut W, max_block: usize) -> Result<usize, Box<dyn Error>> {
+    let mut combined = 0u32;
+    let mut total = 0;
+    loop {
+        match reader.read_u48()? {
+            BLOCK_MAGIC => {
+                let expected = reader.read_bits(32)?;
+                let block = decode_block(reader, max_block)?;
+                let crc = !crc32_update(!0, &block);
+                if crc != expected {
+                    return Err(From::from("[-] bzip2 block CRC mismatch."));
+                }
+                combined = combined.rotate_left(1) ^ crc;
+                out.write_all(&block)?;
+                total += block.len();
+            }
+            END_MAGIC => {
+                if reader.read_bits(32)? != combined {
+                    return Err(From::from("[-] bzip2 stream CRC mismatch."));
+                }
+                return Ok(total);
+            }
+            _ => return Err(From::from("[-] Invalid bzip2 block header.")),
+        }
+    }
+}
+
+/// Decodes a block after its CRC: Huffman tables, MTF/RLE2, inverse BWT and RLE1
+fn decode_block<R: BitRead>(reader: &mut R, max_block: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if reader.read_bit()? {
+        return Err(From::from("[-] Randomized bzip2 blocks are not supported."));
+    }
+    let orig_ptr = reader.read_bits(24)? as usize;
+
+    // Bytes used in the block: 16 bits for ranges of 16 bytes, then 16 bits for each used range
+    let ranges = reader.read_bits(16)?;
+    let mut used = Vec::new();
+    for i in 0..16 {
+        if ranges & (0x8000 >> i) != 0 {
+            let bytes = reader.read_bits(16)?;
+            for j in 0..16 {
+                if bytes & (0x8000 >> j) != 0 {
+                    used.push((i * 16 + j) as u8);
+                }
+            }
+        }
+    }
+    if used.is_empty() {
+        return Err(From::from("[-] bzip2 block without symbols."));
+    }
+    // RUNA, RUNB, MTF positions 1 to n-1 and the end of block
+    let alphabet = used.len() + 2;
+
+    let nb_tables = reader.read_bits(3)? as usize;
+    if !(2..=6).contains(&nb_tables) {
+        return Err(From::from("[-] Invalid number of bzip2 Huffman tables."));
+    }
+    let nb_selectors = reader.read_bits(15)? as usize;
+    if nb_selectors == 0 {
+        return Err(From::from("[-] bzip2 block without selectors."));
+    }
+
+    // Selectors: unary coded MTF positions of the table numbers
+    let mut tables_mtf: Vec<u8> = (0..nb_tables as u8).collect();
+    let mut selectors = Vec::with_capacity(nb_selectors.min(MAX_SELECTORS));
+    for _ in 0..nb_selectors {
+        let mut position = 0;
+        while reader.read_bit()? {
+            position += 1;
+            if position >= nb_tables {
+                return Err(From::from("[-] Invalid bzip2 selector."));
+            }
+        }
+        let table = tables_mtf.remove(position);
+        tables_mtf.insert(0, table);
+        if selectors.len() < MAX_SELECTORS {
+            selectors.push(table);
+        }
+    }
+
+    // Delta coded lengths of each table
+    let mut tables = Vec::with_capacity(nb_tables);
+    for _ in 0..nb_tables {
+        let mut len = reader.read_bits(5)? as i32;
+        let mut lengths = vec![0u8; alphabet];
+        for l in lengths.iter_mut() {
+            loop {
+                if !(1..=20).contains(&len) {
+                    return Err(From::from("[-] Invalid bzip2 code length."));
+                }
+                if !reader.read_bit()? {
+                    break;
+                }
+                len += if reader.read_bit()? { -1 } else { 1 };
+            }
+            *l = len as u8;
+        }
+        tables.push(CanonicalCode::from_lengths(&lengths)?);
+    }
+
+    // Huffman decode the MTF positions and undo the zero run length coding
+    let end_of_block = (alphabet - 1) as u32;
+    let mut mtf: Vec<u8> = (0..used.len()).map(|i| i as u8).collect();
+    let mut block: Vec<u8> = Vec::new();
+    let mut run = 0usize;
+    let mut run_bit = 1usize;
+    let mut decoded = 0usize;
+    loop {
+        let selector = *selectors.get(decoded / GROUP_SIZE).ok_or("[-] bzip2 selectors exhausted.")?;
+        let symbol = tables[selector as usize].decode(reader)?;
+        decoded += 1;
+
+        if symbol <= 1 {
+            // RUNA adds 1, RUNB adds 2, times the weight of the digit
+            run += run_bit << symbol;
+            run_bit <<= 1;
+            if run > max_block {
+                return Err(From::from("[-] bzip2 block too big."));
+            }
+            continue;
+        }
+        if run > 0 {
+            block.extend(std::iter::repeat_n(used[mtf[0] as usize], run));
+            run = 0;
+            run_bit = 1;
+        }
+        if symbol == end_of_block {
+            break;
+        }
+        let index = mtf.remove(symbol as usize - 1);
+        mtf.insert(0, index);
+        block.push(used[index as usize]);
+        if block.len() > max_block {
+            return Err(From::from("[-] bzip2 block too big."));
+        }
+    }
+    if block.len() > max_block {
+        return Err(From::from("[-] bzip2 block too big."));
+    }
+    if orig_ptr >= block.len() {
+        return Err(From::from("[-] Invalid bzip2 BWT origin."));
+    }
+
+    Ok(undo_initial_rle(&transform::inverse_bwt(&block, orig_ptr)?))
+}
+
+/// Expands the runs of 4 to 255 bytes bzip2 codes as 4 bytes and a count
+fn undo_initial_rle(data: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        let mut same = 1;
+        while same < 4 && i + same < data.len() && data[i + same] == b {
+            same += 1;
+        }
+        ret.extend(std::iter::repeat_n(b, same));
+        i += same;
+        if same == 4 && i < data.len() {
+            ret.extend(std::iter::repeat_n(b, data[i] as usize));
+            i += 1;
+        }
+    }
+    ret
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_bin::BinFile;
+
+    fn decompress_path(path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut file = BinFile::open(&String::from(path))?;
+        let mut out = Vec::new();
+        let n = decompress(&mut file, &mut out)?;
+        assert_eq!(n, out.len());
+        Ok(out)
+    }
+
+    #[test]
+    fn check_small_files() -> Result<(), Box<dyn Error>> {
+        assert_eq!(decompress_path("./data/lazy_dog.txt.bz2")?, std::fs::read("./data/lazy_dog.txt")?);
+        assert_eq!(decompress_path("./data/tara.txt.bz2")?, std::fs::read("./data/tara.txt")?);
+        assert_eq!(decompress_path("./data/empty.txt.bz2")?, b"");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_multiple_blocks_and_streams() -> Result<(), Box<dyn Error>> {
+        // bzip2 -1 of these lines: several blocks, long runs and all 6 tables
+        let mut expected = Vec::new();
+        for i in 0..4000u32 {
+            expected.extend(format!("{:05} The quick brown fox jumps over the lazy dog {}\n", i, "z".repeat((i % 300) as usize)).bytes());
+        }
+        assert_eq!(decompress_path("./data/lines.txt.bz2")?, expected);
+
+        // Two streams back to back, as written by parallel bzip2 tools
+        let mut expected = std::fs::read("./data/lazy_dog.txt")?;
+        expected.extend(std::fs::read("./data/tara.txt")?);
+        assert_eq!(decompress_path("./data/concat.bz2")?, expected);
+
+        // Trailing data that is not a stream is ignored, a short one too
+        let mut data = std::fs::read("./data/concat.bz2")?;
+        for garbage in [&b"BZh0 and more"[..], b"B", b"\0\0\0\0\0\0"] {
+            let mut with_garbage = data.clone();
+            with_garbage.extend_from_slice(garbage);
+            let mut out = Vec::new();
+            assert_eq!(decompress(&mut BinFile::new(&with_garbage[..], "garbage"), &mut out)?, expected.len());
+            assert_eq!(out, expected);
+        }
+        data[0] = b'C';
+        assert!(decompress(&mut BinFile::new(&data[..], "garbage"), &mut Vec::new()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupted() {
+        let mut data = std::fs::read("./data/lazy_dog.txt.bz2").unwrap();
+        assert!(decompress(&mut BinFile::new(&data[..], "corrupted"), &mut Vec::new()).is_ok());
+        let n = data.len();
+        data[n - 8] ^= 0x10;
+        assert!(decompress(&mut BinFile::new(&data[..], "corrupted"), &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use crate::file_bin::BitRead;
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+
+/// A single code: its `len` lowest bits in `bits`, sent highest first. A length of 0 means no code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Code {
+    pub bits: u32,
+    pub len: u8,
+}
+
+/// Canonical Huffman code, described only by how many codes each length has and the order of the
+/// symbols. Codes of the same length are consecutive integers, shorter codes come first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanonicalCode {
+    // Number of codes for each length, index 0 is unused
+    counts: Vec<u32>,
+    // Symbols in code order
+    symbols: Vec<u32>,
+}
+
+impl CanonicalCode {
+    /// Creates a code from per-length counts (`counts[len]`) and the symbols listed in code order
+    pub fn new(counts: Vec<u32>, symbols: Vec<u32>) -> Result<Self, Box<dyn Error>> {
+        let total: usize = counts.iter().skip(1).map(|&c| c as usize).sum();
+        if total != symbols.len() {
+            return Err(From::from("[-] Code counts do not match the number of symbols."));
+        }
+        if counts.len() > 33 {
+            return Err(From::from("[-] Codes longer than 32 bits are not supported."));
+        }
+        // Kraft inequality: an over-subscribed set of lengths cannot be a prefix code
+        let mut left = 1u64;
+        for &count in counts.iter().skip(1) {
+            left <<= 1;
+            if count as u64 > left {
+                return Err(From::from("[-] Over-subscribed Huffman code."));
+            }
+            left -= count as u64;
+        }
+        let mut counts = counts;
+        if counts.is_empty() {
+            counts.push(0);
+        }
+
+        Ok(CanonicalCode { counts, symbols })
+    }
+
+    /// Creates a code from the code length of every symbol (0 for unused symbols).
+    /// Symbols of the same length are ordered by value, as in Deflate or bzip2.
+    pub fn from_lengths(lengths: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let max = lengths.iter().copied().max().unwrap_or(0) as usize;
+        let mut counts = vec![0u32; max + 1];
+        let mut symbols = Vec::new();
+        for (len, count) in counts.iter_mut().enumerate().skip(1) {
+            for (symbol, &l) in lengths.iter().enumerate() {
+                if l as usize == len {
+                    *count += 1;
+                    symbols.push(symbol as u32);
+                }
+            }
+        }
+
+        CanonicalCode::new(counts, symbols)
+    }
+
+    /// Builds an optimal code with no code longer than `max_len` for the given symbol weights
+    pub fn from_frequencies(freqs: &[u64], max_len: u8) -> Self {
+        CanonicalCode::from_lengths(&lengths_from_frequencies(freqs, max_len))
+            .expect("Huffman lengths always satisfy the Kraft inequality")
+    }
+
+    /// Number of codes of each length, index 0 is unused
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Symbols in code order
+    pub fn symbols(&self) -> &[u32] {
+        &self.symbols
+    }
+
+    /// Length of the longest code
+    pub fn max_len(&self) -> u8 {
+        (self.counts.len() - 1) as u8
+    }
+
+    /// Returns the code of every symbol below `alphabet`, indexed by symbol
+    pub fn codes(&self, alphabet: usize) -> Vec<Code> {
+        let mut codes = vec![Code::default(); alphabet];
+        // A complete code of 32 bit lengths ends at 1 << 32, past a u32
+        let mut code = 0u64;
+        let mut symbols = self.symbols.iter();
+        for (len, &count) in self.counts.iter().enumerate().skip(1) {
+            for _ in 0..count {
+                let symbol = *symbols.next().unwrap() as usize;
+                if symbol < alphabet {
+                    codes[symbol] = Code { bits: code as u32, len: len as u8 };
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
+        codes
+    }
+
+    /// Returns the code length of every symbol below `alphabet`, indexed by symbol
+    pub fn lengths(&self, alphabet: usize) -> Vec<u8> {
+        self.codes(alphabet).iter().map(|code| code.len).collect()
+    }
+
+    /// Reads bits until they form a complete code and returns its symbol
+    pub fn decode<R: BitRead>(&self, reader: &mut R) -> Result<u32, Box<dyn Error>> {
+        // Current code, first code of the current length and index of its symbol
+        let mut code = 0usize;
+        let mut first = 0usize;
+        let mut index = 0usize;
+        for &count in self.counts.iter().skip(1) {
+            code |= reader.read_bit()? as usize;
+            let count = count as usize;
+            if code - first < count {
+                return Ok(self.symbols[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(From::from("[-] Invalid Huffman code in stream."))
+    }
+}
+
+/// Computes Huffman code lengths for the given weights, no longer than `max_len`.
+/// Symbols with a weight of 0 get no code, a lone symbol gets a 1 bit code.
+pub fn lengths_from_frequencies(freqs: &[u64], max_len: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&i| freqs[i] != 0).collect();
+    match used.len() {
+        0 => return lengths,
+        1 => {
+            lengths[used[0]] = 1;
+            return lengths;
+        }
+        n => assert!((max_len as u32) < 64 && n as u64 <= 1u64 << max_len, "[-] Too many symbols for the code length limit."),
+    }
+
+    // Merge the two lightest nodes until a single one remains. Leaves are 0..n, every merge creates
+    // a node with a bigger index than its children.
+    let n = used.len();
+    let mut heap = BinaryHeap::with_capacity(n);
+    for (i, &symbol) in used.iter().enumerate() {
+        heap.push(Reverse((freqs[symbol], i)));
+    }
+    let mut parent = vec![0usize; 2 * n - 1];
+    let mut next = n;
+    while heap.len() > 1 {
+        let Reverse((first, a)) = heap.pop().unwrap();
+        let Reverse((second, b)) = heap.pop().unwrap();
+        parent[a] = next;
+        parent[b] = next;
+        heap.push(Reverse((first + second, next)));
+        next += 1;
+    }
+
+    // Depths, from the root (last node) down to the leaves
+    let mut depth = vec![0usize; 2 * n - 1];
+    for i in (0..2 * n - 2).rev() {
+        depth[i] = depth[parent[i]] + 1;
+    }
+
+    // Count the lengths and squeeze the ones over the limit (ITU T.81 Annex K.2, Figure K.3)
+    let longest = depth[..n].iter().copied().max().unwrap();
+    let mut counts = vec![0u64; longest.max(max_len as usize) + 1];
+    for &d in &depth[..n] {
+        counts[d] += 1;
+    }
+    for i in (max_len as usize + 1..=longest).rev() {
+        while counts[i] > 0 {
+            let mut j = i - 2;
+            while counts[j] == 0 {
+                j -= 1;
+            }
+            counts[i] -= 2;
+            counts[i - 1] += 1;
+            counts[j + 1] += 2;
+            counts[j] -= 1;
+        }
+    }
+
+    // Hand the shortest lengths to the heaviest symbols
+    let mut by_weight = used;
+    by_weight.sort_by_key(|&symbol| (Reverse(freqs[symbol]), symbol));
+    let mut symbols = by_weight.into_iter();
+    for (len, &count) in counts.iter().enumerate() {
+        for _ in 0..count {
+            lengths[symbols.next().unwrap()] = len as u8;
+        }
+    }
+
+    lengths
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Bits(Vec<bool>);
+
+    impl BitRead for Bits {
+        fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+            if self.0.is_empty() {
+                return Err(From::from("end of bits"));
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    #[test]
+    fn check_codes_from_lengths() -> Result<(), Box<dyn Error>> {
+        // Example from RFC 1951, section 3.2.2
+        let code = CanonicalCode::from_lengths(&[3, 3, 3, 3, 3, 2, 4, 4])?;
+        let bits: Vec<u32> = code.codes(8).iter().map(|c| c.bits).collect();
+        assert_eq!(bits, vec![0b010, 0b011, 0b100, 0b101, 0b110, 0b00, 0b1110, 0b1111]);
+
+        let mut stream = Bits(vec![true, true, true, false, false, false, true, true, false]);
+        assert_eq!(code.decode(&mut stream)?, 6);
+        assert_eq!(code.decode(&mut stream)?, 5);
+        assert_eq!(code.decode(&mut stream)?, 4);
+
+        assert!(CanonicalCode::from_lengths(&[1, 1, 1]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_length_limit() {
+        // Fibonacci weights give the deepest possible tree
+        let mut freqs = vec![1u64, 1];
+        while freqs.len() < 30 {
+            let n = freqs.len();
+            freqs.push(freqs[n - 1] + freqs[n - 2]);
+        }
+
+        let unlimited = lengths_from_frequencies(&freqs, 32);
+        assert_eq!(*unlimited.iter().max().unwrap(), 29);
+
+        let limited = lengths_from_frequencies(&freqs, 12);
+        assert_eq!(*limited.iter().max().unwrap(), 12);
+        let kraft: f64 = limited.iter().map(|&l| 0.5f64.powi(l as i32)).sum();
+        assert!((kraft - 1.0).abs() < 1e-12);
+        assert!(CanonicalCode::from_lengths(&limited).is_ok());
+
+        // A complete code down to 32 bits
+        let mut lengths: Vec<u8> = (1..=32).collect();
+        lengths.push(32);
+        let code = CanonicalCode::from_lengths(&lengths).unwrap();
+        let codes = code.codes(lengths.len());
+        assert_eq!(codes[31], Code { bits: u32::MAX - 1, len: 32 });
+        assert_eq!(codes[32], Code { bits: u32::MAX, len: 32 });
+        let mut stream = Bits(vec![true; 32]);
+        assert_eq!(code.decode(&mut stream).unwrap(), 32);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::canonical::{CanonicalCode, Code};
+use crate::file_bin::{BitRead, BitWrite};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::hash::Hash;
+
+/// Longest code of a `HuffmanCode`
+pub const MAX_CODE_LEN: u8 = 32;
+
+/// Huffman code over any symbol type: u16 tokens, words, enum values...
+/// Symbols are kept sorted, so the same weights always give the same code whatever their order.
+#[derive(Clone, Debug)]
+pub struct HuffmanCode<S: Ord + Hash + Clone> {
+    code: CanonicalCode,
+    // Symbols sorted, the canonical code works on their index
+    symbols: Vec<S>,
+    index: HashMap<S, usize>,
+    codes: Vec<Code>,
+}
+
+impl<S: Ord + Hash + Clone> HuffmanCode<S> {
+    /// Builds the code from `(symbol, weight)` pairs. Weights of repeated symbols add up and
+    /// symbols with a weight of 0 get no code.
+    pub fn from_weights<I: IntoIterator<Item = (S, u64)>>(weights: I) -> Self {
+        let mut merged: HashMap<S, u64> = HashMap::new();
+        for (symbol, weight) in weights {
+            *merged.entry(symbol).or_insert(0) += weight;
+        }
+        let mut pairs: Vec<(S, u64)> = merged.into_iter().filter(|&(_, w)| w != 0).collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let freqs: Vec<u64> = pairs.iter().map(|&(_, w)| w).collect();
+        let symbols: Vec<S> = pairs.into_iter().map(|(s, _)| s).collect();
+        let code = CanonicalCode::from_frequencies(&freqs, MAX_CODE_LEN);
+        HuffmanCode::with_code(symbols, code)
+    }
+
+    /// Builds the code from sorted symbols and their code lengths, as stored next to coded data
+    pub fn from_lengths(symbols: Vec<S>, lengths: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if symbols.len() != lengths.len() {
+            return Err(From::from("[-] Symbols and code lengths do not match."));
+        }
+        if symbols.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(From::from("[-] Symbols must be sorted and unique."));
+        }
+        Ok(HuffmanCode::with_code(symbols, CanonicalCode::from_lengths(lengths)?))
+    }
+
+    fn with_code(symbols: Vec<S>, code: CanonicalCode) -> Self {
+        let index = symbols.iter().cloned().enumerate().map(|(i, s)| (s, i)).collect();
+        let codes = code.codes(symbols.len());
+        HuffmanCode { code, symbols, index, codes }
+    }
+
+    /// Symbols with a code, sorted
+    pub fn symbols(&self) -> &[S] {
+        &self.symbols
+    }
+
+    /// Code length of every symbol, in the order of `symbols()`
+    pub fn lengths(&self) -> Vec<u8> {
+        self.codes.iter().map(|c| c.len).collect()
+    }
+
+    /// Code of a symbol, None if it has none
+    pub fn code(&self, symbol: &S) -> Option<Code> {
+        self.index.get(symbol).map(|&i| self.codes[i])
+    }
+
+    /// Writes the codes of the symbols, returns the number of bits written
+    pub fn encode<I, W>(&self, symbols: I, writer: &mut W) -> Result<usize, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = S>,
+        W: BitWrite,
+    {
+        let mut ret = 0;
+        for symbol in symbols {
+            let code = self.code(&symbol).ok_or("[-] Symbol without a Huffman code.")?;
+            writer.write_bits(code.bits, code.len)?;
+            ret += code.len as usize;
+        }
+        Ok(ret)
+    }
+
+    /// Reads a single symbol
+    pub fn decode_symbol<R: BitRead>(&self, reader: &mut R) -> Result<S, Box<dyn Error>> {
+        Ok(self.symbols[self.code.decode(reader)? as usize].clone())
+    }
+
+    /// Iterator reading symbols from `reader`, it never ends by itself: use `take()`
+    pub fn decode<'a, R: BitRead>(&'a self, reader: &'a mut R) -> Decode<'a, S, R> {
+        Decode { code: self, reader }
+    }
+}
+
+impl HuffmanCode<u8> {
+    /// Builds a byte code from the weight of every byte, e.g. counted once over samples and then
+    /// used on many messages
+    pub fn from_frequencies(freqs: &[u64; 256]) -> Self {
+        HuffmanCode::from_weights((0..=255u8).zip(freqs.iter().copied()))
+    }
+}
+
+/// Symbols read by `HuffmanCode::decode`
+pub struct Decode<'a, S: Ord + Hash + Clone, R: BitRead> {
+    code: &'a HuffmanCode<S>,
+    reader: &'a mut R,
+}
+
+impl<S: Ord + Hash + Clone, R: BitRead> Iterator for Decode<'_, S, R> {
+    type Item = Result<S, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.code.decode_symbol(self.reader))
+    }
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_bin::{BitReader, BitWriter};
+
+    #[derive(Default)]
+    struct Bits(Vec<bool>, usize);
+
+    impl BitWrite for Bits {
+        fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>> {
+            self.0.push(bit);
+            Ok(self.0.len().is_multiple_of(8))
+        }
+    }
+
+    impl BitRead for Bits {
+        fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+            let bit = *self.0.get(self.1).ok_or("end of bits")?;
+            self.1 += 1;
+            Ok(bit)
+        }
+    }
+
+    #[test]
+    fn check_words() -> Result<(), Box<dyn Error>> {
+        let text = "the cat and the dog and the bird";
+        let code = HuffmanCode::from_weights(text.split(' ').map(|w| (w, 1)));
+        assert_eq!(code.symbols(), &["and", "bird", "cat", "dog", "the"]);
+        assert!(code.code(&"the").unwrap().len < code.code(&"cat").unwrap().len);
+        assert!(code.code(&"fish").is_none());
+
+        let mut bits = Bits::default();
+        let n = code.encode(text.split(' '), &mut bits)?;
+        assert_eq!(n, bits.0.len());
+        let decoded: Vec<&str> = code.decode(&mut bits).take(8).collect::<Result<_, _>>()?;
+        assert_eq!(decoded.join(" "), text);
+
+        // The same code comes back from the stored symbols and lengths
+        let stored = HuffmanCode::from_lengths(code.symbols().to_vec(), &code.lengths())?;
+        assert_eq!(stored.code(&"dog"), code.code(&"dog"));
+        assert!(code.encode(vec!["fish"], &mut bits).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_tokens_and_bytes() -> Result<(), Box<dyn Error>> {
+        let tokens: Vec<u16> = (0..2000u32).map(|i| (i * i % 1009) as u16 * 60).collect();
+        let code = HuffmanCode::from_weights(tokens.iter().map(|&t| (t, 1)));
+        let mut bits = Bits::default();
+        code.encode(tokens.iter().copied(), &mut bits)?;
+        let decoded: Vec<u16> = code.decode(&mut bits).take(tokens.len()).collect::<Result<_, _>>()?;
+        assert_eq!(decoded, tokens);
+
+        let file_tara = std::fs::read("./data/tara.txt")?;
+        let code = HuffmanCode::from_weights(file_tara.iter().map(|&b| (b, 1)));
+        assert_eq!(code.symbols(), b"\n art");
+        let mut bits = Bits::default();
+        assert_eq!(code.encode(file_tara.iter().copied(), &mut bits)?, 21);
+
+        // Table computed once from a sample, then used on messages
+        let mut freqs = [0u64; 256];
+        for &b in std::fs::read("./data/lazy_dog.txt")?.iter() {
+            freqs[b as usize] += 1;
+        }
+        let code = HuffmanCode::from_frequencies(&freqs);
+        assert_eq!(code.symbols().len(), 29);
+        for message in [&b"the dog"[..], b"a lazy fox", b""] {
+            let mut writer = BitWriter::new();
+            code.encode(message.iter().copied(), &mut writer)?;
+            let coded = writer.into_bytes();
+            let decoded: Vec<u8> = code.decode(&mut BitReader::new(&coded)).take(message.len()).collect::<Result<_, _>>()?;
+            assert_eq!(decoded, message);
+        }
+        assert!(code.encode(b"THE DOG".iter().copied(), &mut Bits::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_large_alphabet() -> Result<(), Box<dyn Error>> {
+        // More symbols than a u16 counts, each one must keep a code
+        let symbols: Vec<u32> = (0..70_000u32).chain(0..1000).collect();
+        let code = HuffmanCode::from_weights(symbols.iter().map(|&s| (s, 1)));
+        assert!(code.code(&65_536).is_some_and(|c| c.len > 0));
+        let mut bits = Bits::default();
+        code.encode(symbols.iter().copied(), &mut bits)?;
+        let decoded: Vec<u32> = code.decode(&mut bits).take(symbols.len()).collect::<Result<_, _>>()?;
+        assert_eq!(decoded, symbols);
+
+        let stored = HuffmanCode::from_lengths(code.symbols().to_vec(), &code.lengths())?;
+        assert_eq!(stored.code(&69_999), code.code(&69_999));
+
+        // Fibonacci weights reach the 32 bit limit
+        let mut weights = vec![1u64, 1];
+        while weights.len() < 40 {
+            let n = weights.len();
+            weights.push(weights[n - 1] + weights[n - 2]);
+        }
+        let code = HuffmanCode::from_weights((0..40u32).zip(weights));
+        assert_eq!(code.lengths().iter().max(), Some(&MAX_CODE_LEN));
+        let symbols: Vec<u32> = (0..40).collect();
+        let mut bits = Bits::default();
+        code.encode(symbols.iter().copied(), &mut bits)?;
+        let decoded: Vec<u32> = code.decode(&mut bits).take(symbols.len()).collect::<Result<_, _>>()?;
+        assert_eq!(decoded, symbols);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+/// CRC-32 as used by zlib, gzip and zip: reflected polynomial 0xEDB88320
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    value: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { value: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.value ^= b as u32;
+            for _ in 0..8 {
+                self.value = (self.value >> 1) ^ (0xedb8_8320 & (self.value & 1).wrapping_neg());
+            }
+        }
+    }
+
+    /// Checksum of the data seen so far
+    pub fn value(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Checksum of a single buffer
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.value()
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_checksum() {
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+        assert_eq!(checksum(b""), 0);
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::code::HuffmanCode;
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// First bytes of a dictionary file
+pub const MAGIC: &[u8; 4] = b"HDIC";
+
+/// Byte code trained on a corpus, shared by the compressor and the decompressor so that
+/// small messages do not carry their own tree
+#[derive(Clone, Debug)]
+pub struct Dictionary {
+    id: u32,
+    code: HuffmanCode<u8>,
+}
+
+impl Dictionary {
+    /// Builds the dictionary from the byte counts of a corpus. Every byte gets a code, bytes
+    /// missing from the corpus the longest ones.
+    pub fn train(freqs: &[u64; 256]) -> Self {
+        let mut weights = [0u64; 256];
+        let total: u64 = freqs.iter().sum();
+        for (w, &f) in weights.iter_mut().zip(freqs.iter()) {
+            // Scaled so that unseen bytes weigh less than any seen one
+            *w = f.saturating_mul(256) + 1;
+        }
+        if total == 0 {
+            weights = [1; 256];
+        }
+        Dictionary::from_code(HuffmanCode::from_frequencies(&weights))
+    }
+
+    fn from_code(code: HuffmanCode<u8>) -> Self {
+        // FNV-1a of the code lengths: the same table always gets the same ID
+        let id = code.lengths().iter().fold(0x811c_9dc5u32, |h, &l| (h ^ l as u32).wrapping_mul(0x0100_0193));
+        Dictionary { id, code }
+    }
+
+    /// Identifier stored in the blocks coded with this dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn code(&self) -> &HuffmanCode<u8> {
+        &self.code
+    }
+
+    /// Writes the dictionary: magic, ID and the code length of the 256 bytes
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.id.to_le_bytes())?;
+        writer.write_all(&self.code.lengths())?;
+        Ok(())
+    }
+
+    /// Reads a dictionary written by `write`
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(From::from("[-] Not a dictionary file."));
+        }
+        let mut lengths = [0u8; 256];
+        reader.read_exact(&mut lengths)?;
+        let dictionary = Dictionary::from_code(HuffmanCode::from_lengths((0..=255).collect(), &lengths)?);
+        if dictionary.id.to_le_bytes() != header[4..] {
+            return Err(From::from("[-] Dictionary ID mismatch."));
+        }
+        Ok(dictionary)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        self.write(&mut std::fs::File::create(path)?)
+    }
+
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Dictionary::read(&mut std::fs::File::open(path)?)
+    }
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_dictionary() -> Result<(), Box<dyn Error>> {
+        let mut freqs = [0u64; 256];
+        for &b in std::fs::read("./data/lazy_dog.txt")?.iter() {
+            freqs[b as usize] += 1;
+        }
+        let dictionary = Dictionary::train(&freqs);
+        assert_eq!(dictionary.code().symbols().len(), 256);
+        assert!(dictionary.code().code(&b'o').unwrap().len < dictionary.code().code(&0xff).unwrap().len);
+
+        let mut saved = Vec::new();
+        dictionary.write(&mut saved)?;
+        assert_eq!(saved.len(), 4 + 4 + 256);
+        let loaded = Dictionary::read(&mut &saved[..])?;
+        assert_eq!(loaded.id(), dictionary.id());
+        assert_eq!(loaded.code().lengths(), dictionary.code().lengths());
+
+        saved[100] ^= 1;
+        assert!(Dictionary::read(&mut &saved[..]).is_err());
+
+        Ok(())
+    }
+}
@@ -1,13 +1,95 @@
 use std::error::Error;
 use std::io::prelude::*;
 use std::io::Write;
-use std::io::SeekFrom;
 use std::fs::File;
 
-pub struct BinFile {
+/// Source of bits, most significant bit of each byte first
+pub trait BitRead {
+    /// Reads a single bit
+    fn read_bit(&mut self) -> Result<bool, Box<dyn Error>>;
+
+    /// Reads `nb` bits (at most 32) and returns them as an unsigned integer, first bit highest
+    fn read_bits(&mut self, nb: u8) -> Result<u32, Box<dyn Error>> {
+        let mut ret = 0u32;
+        for _ in 0..nb {
+            ret = (ret << 1) | self.read_bit()? as u32;
+        }
+        Ok(ret)
+    }
+}
+
+/// Sink of bits, most significant bit of each byte first
+pub trait BitWrite {
+    /// Writes a single bit, returns true when a full byte got written out
+    fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>>;
+
+    /// Writes the `nb` lowest bits (at most 32) of `value`, highest first
+    fn write_bits(&mut self, value: u32, nb: u8) -> Result<(), Box<dyn Error>> {
+        for i in (0..nb).rev() {
+            self.write_bit((value >> i) & 1 != 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Bits written to memory
+#[derive(Clone, Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    offset: u8,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+
+    /// Returns the bytes written, the last one padded with zeros
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl BitWrite for BitWriter {
+    fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>> {
+        if self.offset == 0 {
+            self.bytes.push(0);
+        }
+        *self.bytes.last_mut().unwrap() |= (bit as u8) << (7 - self.offset);
+        self.offset = (self.offset + 1) % 8;
+        Ok(self.offset == 0)
+    }
+}
+
+/// Bits read from memory
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+}
+
+impl BitRead for BitReader<'_> {
+    fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+        let byte = self.bytes.get(self.position / 8).ok_or("[-] Not enough bits.")?;
+        let ret = (byte >> (7 - self.position % 8)) & 1 != 0;
+        self.position += 1;
+        Ok(ret)
+    }
+}
+
+/// Bits read from or written to a stream, a file by default
+pub struct BinFile<F = File> {
     offset: u8,
     buffer: u8,
-    file: std::fs::File,
+    file: F,
+    // Bytes read or written so far
+    position: u64,
 
     pub size: Option<usize>,
     pub path: String,
@@ -15,35 +97,50 @@ pub struct BinFile {
 
 impl BinFile {
     pub fn create(path: &String) -> Result<BinFile, Box<dyn Error>> {
-        Ok(BinFile {
-            offset: 0u8,
-            buffer: 0u8,
-            file: std::fs::File::create(path)?,
-            size: None,
-            path: path.clone(),
-        })
+        Ok(BinFile::new(std::fs::File::create(path)?, path))
     }
 
     pub fn open(path: &String) -> Result<BinFile, Box<dyn Error>> {
-        Ok(BinFile {
+        Ok(BinFile::new(File::open(path)?, path))
+    }
+}
+
+impl<F> BinFile<F> {
+    /// Wraps any stream, `path` only names it in messages
+    pub fn new(file: F, path: &str) -> BinFile<F> {
+        BinFile {
             offset: 0u8,
             buffer: 0u8,
-            file: File::open(path)?,
+            file,
+            position: 0,
             size: None,
-            path: path.clone(),
-        })
+            path: String::from(path),
+        }
+    }
+
+    /// Number of bytes read or written so far
+    pub fn tell(&self) -> u64 {
+        self.position
     }
 
-    pub fn tell(&mut self) -> Result<u64, Box<dyn Error>> {
-        Ok(self.file.seek(SeekFrom::Current(0))?)
+    pub fn into_inner(self) -> F {
+        self.file
     }
 
+    pub fn align(&mut self) {
+        // Drops the bits left in the current byte, the next read starts on a new byte
+        self.offset = 0;
+    }
+}
+
+impl<F: Read> BinFile<F> {
     pub fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
         // If we need a new byte, read it from the file
         if self.offset == 0 {
             let mut t = [0;1];
             self.file.read_exact(&mut t)?;
             self.buffer = t[0];
+            self.position += 1;
         }
         // Extract the bit as boolean
         let ret = (self.buffer & (1 << (7 - self.offset))) != 0;
@@ -61,19 +158,36 @@ impl BinFile {
                 false => ret <<= 1
             };
         }
-        print!("{}", ret);
         Ok(ret)
     }
 
     pub fn read_bytes(&mut self, nb: usize) -> Result<Box<[u8]>, Box<dyn Error>> {
-        // Just loop nb times and call self.read_byte()
         let mut ret = vec![0u8; nb];
-        for i in 0..nb {
-            ret[i] = self.read_byte()?;
+        // On a byte boundary the bytes come straight from the file
+        if self.offset == 0 {
+            self.file.read_exact(&mut ret)?;
+            self.position += nb as u64;
+            return Ok(ret.into_boxed_slice());
+        }
+        // Otherwise just loop nb times and call self.read_byte()
+        for b in ret.iter_mut() {
+            *b = self.read_byte()?;
         }
         Ok(ret.into_boxed_slice())
     }
 
+    pub fn read_size(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut buf = [0u8; 8];
+        if self.file.read_exact(&mut buf).is_err() {
+            return Err(From::from("[-] Wrong file format."));
+        }
+        self.position += 8;
+        self.size = Some(usize::from_le_bytes(buf));
+        Ok(self.size.unwrap())
+    }
+}
+
+impl<F: Write> BinFile<F> {
     pub fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>> {
         match bit {
             true => {
@@ -87,6 +201,7 @@ impl BinFile {
 
         if self.offset == 8 {
             self.file.write_all(&[self.buffer])?;
+            self.position += 1;
             self.offset = 0;
             self.buffer = 0;
             return Ok(true);
@@ -107,7 +222,13 @@ impl BinFile {
     }
 
     pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
-        // Just loop through bytes and call self.write_byte()
+        // On a byte boundary the bytes go straight to the file
+        if self.offset == 0 {
+            self.file.write_all(bytes)?;
+            self.position += bytes.len() as u64;
+            return Ok(());
+        }
+        // Otherwise just loop through bytes and call self.write_byte()
         for b in bytes {
             self.write_byte(*b)?;
         }
@@ -121,16 +242,16 @@ impl BinFile {
         }
         Ok(())
     }
+}
 
-    pub fn read_size(&mut self) -> Result<usize, Box<dyn Error>> {
-        let mut buf = [0u8; 8];
-        let n = self.file.read(&mut buf)?;
-
-        if n != 8 {
-            panic!("[+] Wrong file format");
-        }
-        self.size = Some(usize::from_le_bytes(buf));
-        Ok(self.size.unwrap())
+impl<F: Read> BitRead for BinFile<F> {
+    fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+        BinFile::read_bit(self)
     }
+}
 
-}
\ No newline at end of file
+impl<F: Write> BitWrite for BinFile<F> {
+    fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>> {
+        BinFile::write_bit(self, bit)
+    }
+}
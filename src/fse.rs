@@ -0,0 +1,531 @@
+use std::error::Error;
+
+/// Smallest accuracy log a table description can carry (RFC 8878, 4.1.1)
+pub const MIN_ACCURACY_LOG: u8 = 5;
+
+/// Index of the highest set bit
+fn highbit(value: u32) -> u8 {
+    (31 - value.leading_zeros()) as u8
+}
+
+/// Bit stream written little-endian and read back from its end, as used by FSE and Huff0.
+/// The last value added is the first one read.
+#[derive(Default)]
+pub struct BackwardWriter {
+    data: Vec<u8>,
+    container: u64,
+    nb: u8,
+}
+
+impl BackwardWriter {
+    pub fn new() -> Self {
+        BackwardWriter::default()
+    }
+
+    /// Appends the `nb` lowest bits (at most 32) of `value`
+    pub fn add_bits(&mut self, value: u32, nb: u8) {
+        let mask = (1u64 << nb) - 1;
+        self.container |= (value as u64 & mask) << self.nb;
+        self.nb += nb;
+        while self.nb >= 8 {
+            self.data.push(self.container as u8);
+            self.container >>= 8;
+            self.nb -= 8;
+        }
+    }
+
+    /// Closes the stream with its 1-bit end mark and returns it
+    pub fn finish(mut self) -> Vec<u8> {
+        self.add_bits(1, 1);
+        if self.nb > 0 {
+            self.data.push(self.container as u8);
+        }
+        self.data
+    }
+}
+
+/// Reads a `BackwardWriter` stream, from the last bit written to the first.
+/// Bits asked for past the beginning of the stream read as 0 and leave it overflowed.
+pub struct BackwardReader<'a> {
+    data: &'a [u8],
+    // Number of bits not read yet, negative once overflowed
+    pos: isize,
+}
+
+impl<'a> BackwardReader<'a> {
+    /// Opens a stream, skipping the padding and end mark of its last byte
+    pub fn new(data: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        match data.last() {
+            Some(&last) if last != 0 => Ok(BackwardReader {
+                data,
+                pos: (data.len() - 1) as isize * 8 + highbit(last as u32) as isize,
+            }),
+            _ => Err(From::from("[-] Missing end mark in backward bit stream.")),
+        }
+    }
+
+    /// Returns the next `nb` bits (at most 32) without consuming them
+    pub fn peek(&self, nb: u8) -> u32 {
+        if nb == 0 || self.pos <= 0 {
+            return 0;
+        }
+        let start = self.pos - nb as isize;
+        if start < 0 {
+            // Only the first bits of the stream are left, the missing ones are zeros
+            return self.bits_from(0, self.pos as u8) << -start;
+        }
+        self.bits_from(start as usize, nb)
+    }
+
+    fn bits_from(&self, start: usize, nb: u8) -> u32 {
+        let first = start / 8;
+        let mut window = 0u64;
+        for (k, &byte) in self.data[first..].iter().take(8).enumerate() {
+            window |= (byte as u64) << (8 * k);
+        }
+        ((window >> (start % 8)) & ((1u64 << nb) - 1)) as u32
+    }
+
+    /// Drops the next `nb` bits
+    pub fn consume(&mut self, nb: u8) {
+        self.pos -= nb as isize;
+    }
+
+    /// Reads the next `nb` bits (at most 32)
+    pub fn read(&mut self, nb: u8) -> u32 {
+        let ret = self.peek(nb);
+        self.consume(nb);
+        ret
+    }
+
+    /// Number of bits left, negative when more bits were read than the stream holds
+    pub fn remaining(&self) -> isize {
+        self.pos
+    }
+
+    /// True when more bits were read than the stream holds
+    pub fn overflowed(&self) -> bool {
+        self.pos < 0
+    }
+}
+
+/// Reads a table description (normalized probabilities) from the start of `data`, returns the
+/// probabilities, the accuracy log and the number of bytes used (RFC 8878, 4.1.1)
+pub fn read_distribution(data: &[u8], max_symbol: u8, max_log: u8) -> Result<(Vec<i16>, u8, usize), Box<dyn Error>> {
+    // Little-endian bits, zeros past the end of data
+    let bits_at = |offset: usize, nb: u8| -> u32 {
+        let mut ret = 0u32;
+        for i in 0..nb as usize {
+            let bit = data.get((offset + i) / 8).map_or(0, |&b| (b >> ((offset + i) % 8)) & 1);
+            ret |= (bit as u32) << i;
+        }
+        ret
+    };
+
+    let log = bits_at(0, 4) as u8 + MIN_ACCURACY_LOG;
+    if log > max_log {
+        return Err(From::from("[-] FSE accuracy log too large."));
+    }
+    let mut offset = 4;
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let mut norm: Vec<i16> = Vec::new();
+    let mut previous_zero = false;
+
+    while remaining > 1 {
+        if previous_zero {
+            // 2-bit repeat flags: 3 means 3 more zeros and another flag follows
+            loop {
+                let repeat = bits_at(offset, 2);
+                offset += 2;
+                norm.extend(std::iter::repeat_n(0, repeat as usize));
+                if repeat != 3 {
+                    break;
+                }
+            }
+        }
+        if norm.len() > max_symbol as usize {
+            return Err(From::from("[-] Too many symbols in FSE table description."));
+        }
+
+        let max = (2 * threshold - 1) - remaining;
+        let low = bits_at(offset, nb_bits - 1) as i32;
+        let mut count = if low < max {
+            offset += nb_bits as usize - 1;
+            low
+        } else {
+            let value = bits_at(offset, nb_bits) as i32;
+            offset += nb_bits as usize;
+            if value >= threshold { value - max } else { value }
+        };
+        // A value of 0 stands for the "less than 1" probability -1
+        count -= 1;
+        remaining -= count.abs();
+        norm.push(count as i16);
+        previous_zero = count == 0;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+
+    let used = offset.div_ceil(8);
+    if remaining != 1 || used > data.len() {
+        return Err(From::from("[-] Corrupted FSE table description."));
+    }
+    Ok((norm, log, used))
+}
+
+/// Writes the table description of normalized probabilities summing to `1 << log`
+pub fn write_distribution(norm: &[i16], log: u8) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::new();
+    let mut push = |value: u32, nb: u8| {
+        for i in 0..nb {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+
+    push((log - MIN_ACCURACY_LOG) as u32, 4);
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let mut symbol = 0;
+    let mut previous_zero = false;
+
+    while symbol < norm.len() && remaining > 1 {
+        if previous_zero {
+            let start = symbol;
+            while norm[symbol] == 0 {
+                symbol += 1;
+            }
+            let mut zeros = symbol - start;
+            while zeros >= 3 {
+                push(3, 2);
+                zeros -= 3;
+            }
+            push(zeros as u32, 2);
+        }
+
+        let mut count = norm[symbol] as i32;
+        symbol += 1;
+        let max = (2 * threshold - 1) - remaining;
+        remaining -= count.abs();
+        count += 1;
+        if count >= threshold {
+            count += max;
+        }
+        push(count as u32, if count < max { nb_bits - 1 } else { nb_bits });
+        previous_zero = count == 1;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+
+    let mut ret = vec![0u8; bits.len().div_ceil(8)];
+    for (i, &bit) in bits.iter().enumerate() {
+        ret[i / 8] |= (bit as u8) << (i % 8);
+    }
+    ret
+}
+
+/// Scales symbol counts to normalized probabilities summing to `1 << log`, every present symbol
+/// keeping at least 1
+pub fn normalize(counts: &[u32], log: u8) -> Vec<i16> {
+    let size = 1i64 << log;
+    let total: i64 = counts.iter().map(|&c| c as i64).sum();
+    let mut norm: Vec<i16> = counts.iter()
+        .map(|&c| match c {
+            0 => 0,
+            c => ((c as i64 * size + total / 2) / total).max(1) as i16,
+        })
+        .collect();
+
+    // Fix rounding errors on the biggest probabilities
+    let mut sum: i64 = norm.iter().map(|&n| n as i64).sum();
+    while sum > size {
+        let i = (0..norm.len()).max_by_key(|&i| norm[i]).unwrap();
+        norm[i] -= 1;
+        sum -= 1;
+    }
+    while sum < size {
+        let i = (0..counts.len()).max_by_key(|&i| counts[i]).unwrap();
+        norm[i] += 1;
+        sum += 1;
+    }
+    norm
+}
+
+/// Positions of the symbols in the state table (RFC 8878, 4.1.1)
+fn spread_symbols(norm: &[i16], log: u8) -> Result<Vec<u8>, Box<dyn Error>> {
+    let size = 1usize << log;
+    let mask = size - 1;
+    let step = (size >> 1) + (size >> 3) + 3;
+    let mut table = vec![0u8; size];
+
+    // "Less than 1" symbols take the last cells
+    let mut high = size as isize - 1;
+    for (symbol, &n) in norm.iter().enumerate() {
+        if n == -1 {
+            if high < 0 {
+                return Err(From::from("[-] Invalid FSE distribution."));
+            }
+            table[high as usize] = symbol as u8;
+            high -= 1;
+        }
+    }
+
+    let mut position = 0usize;
+    for (symbol, &n) in norm.iter().enumerate() {
+        for _ in 0..n.max(0) {
+            table[position] = symbol as u8;
+            position = (position + step) & mask;
+            while position as isize > high {
+                position = (position + step) & mask;
+            }
+        }
+    }
+    if position != 0 {
+        return Err(From::from("[-] Invalid FSE distribution."));
+    }
+    Ok(table)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Cell {
+    symbol: u8,
+    nb_bits: u8,
+    baseline: u16,
+}
+
+/// FSE decoding table
+#[derive(Clone, Debug)]
+pub struct DecodeTable {
+    log: u8,
+    cells: Vec<Cell>,
+}
+
+impl DecodeTable {
+    pub fn new(norm: &[i16], log: u8) -> Result<Self, Box<dyn Error>> {
+        let symbols = spread_symbols(norm, log)?;
+        let mut next: Vec<u32> = norm.iter().map(|&n| if n == -1 { 1 } else { n.max(0) as u32 }).collect();
+        let cells = symbols.iter()
+            .map(|&symbol| {
+                let x = next[symbol as usize];
+                next[symbol as usize] += 1;
+                let nb_bits = log - highbit(x);
+                Cell { symbol, nb_bits, baseline: ((x << nb_bits) - (1 << log)) as u16 }
+            })
+            .collect();
+        Ok(DecodeTable { log, cells })
+    }
+
+    /// Reads an initial state
+    pub fn init_state(&self, reader: &mut BackwardReader) -> usize {
+        reader.read(self.log) as usize
+    }
+
+    /// Symbol of a state
+    pub fn symbol(&self, state: usize) -> u8 {
+        self.cells[state].symbol
+    }
+
+    /// Moves to the next state
+    pub fn update(&self, state: usize, reader: &mut BackwardReader) -> usize {
+        let cell = self.cells[state];
+        cell.baseline as usize + reader.read(cell.nb_bits) as usize
+    }
+
+    /// Decodes a stream written by `EncodeTable::encode_interleaved` (RFC 8878, 4.2.1.2)
+    pub fn decode_interleaved(&self, data: &[u8], max_symbols: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut reader = BackwardReader::new(data)?;
+        let mut states = [self.init_state(&mut reader), self.init_state(&mut reader)];
+        if reader.overflowed() {
+            return Err(From::from("[-] FSE stream too short."));
+        }
+
+        let mut ret = Vec::new();
+        for i in (0..2).cycle() {
+            if ret.len() + 2 > max_symbols {
+                return Err(From::from("[-] Too many symbols in FSE stream."));
+            }
+            ret.push(self.symbol(states[i]));
+            states[i] = self.update(states[i], &mut reader);
+            if reader.overflowed() {
+                // The other state holds the last symbol
+                ret.push(self.symbol(states[1 - i]));
+                break;
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Decodes exactly `count` symbols (at least 2) written by `EncodeTable::encode_interleaved`,
+    /// without relying on the stream end to find the last symbols
+    pub fn decode_exact(&self, data: &[u8], count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut reader = BackwardReader::new(data)?;
+        let mut states = [self.init_state(&mut reader), self.init_state(&mut reader)];
+
+        let mut ret = Vec::with_capacity(count);
+        for i in 0..count {
+            ret.push(self.symbol(states[i % 2]));
+            // The last two symbols are the states the encoder started from
+            if i + 2 < count {
+                states[i % 2] = self.update(states[i % 2], &mut reader);
+            }
+        }
+        if reader.remaining() != 0 {
+            return Err(From::from("[-] FSE stream size mismatch."));
+        }
+        Ok(ret)
+    }
+}
+
+/// FSE encoding table
+#[derive(Clone, Debug)]
+pub struct EncodeTable {
+    log: u8,
+    states: Vec<u16>,
+    // Per symbol: offset into the state table and bit count helper
+    transforms: Vec<(i32, u32)>,
+}
+
+impl EncodeTable {
+    pub fn new(norm: &[i16], log: u8) -> Result<Self, Box<dyn Error>> {
+        let size = 1u32 << log;
+        let symbols = spread_symbols(norm, log)?;
+
+        // First slot of each symbol in the state table
+        let mut cumul = vec![0u32; norm.len() + 1];
+        for (s, &n) in norm.iter().enumerate() {
+            cumul[s + 1] = cumul[s] + if n == -1 { 1 } else { n.max(0) as u32 };
+        }
+        let mut states = vec![0u16; size as usize];
+        for (u, &symbol) in symbols.iter().enumerate() {
+            states[cumul[symbol as usize] as usize] = (size + u as u32) as u16;
+            cumul[symbol as usize] += 1;
+        }
+
+        let mut total = 0i32;
+        let transforms = norm.iter()
+            .map(|&n| match n {
+                0 => (0, ((log as u32 + 1) << 16) - size),
+                -1 | 1 => {
+                    total += 1;
+                    (total - 2, ((log as u32) << 16) - size)
+                }
+                n => {
+                    let max_bits_out = log - highbit(n as u32 - 1);
+                    let min_state_plus = (n as u32) << max_bits_out;
+                    total += n as i32;
+                    (total - 2 * n as i32, ((max_bits_out as u32) << 16) - min_state_plus)
+                }
+            })
+            .collect();
+
+        Ok(EncodeTable { log, states, transforms })
+    }
+
+    /// Initial state for the last symbol of a stream, costs no bits
+    pub fn init_state(&self, symbol: u8) -> u32 {
+        let (find, delta) = self.transforms[symbol as usize];
+        let nb_out = (delta + (1 << 15)) >> 16;
+        let value = (nb_out << 16) - delta;
+        self.states[((value >> nb_out) as i32 + find) as usize] as u32
+    }
+
+    /// Writes the bits leading from `symbol` to `state`, returns the new state
+    pub fn encode(&self, writer: &mut BackwardWriter, state: u32, symbol: u8) -> u32 {
+        let (find, delta) = self.transforms[symbol as usize];
+        let nb_out = ((state + delta) >> 16) as u8;
+        writer.add_bits(state, nb_out);
+        self.states[((state >> nb_out) as i32 + find) as usize] as u32
+    }
+
+    /// Writes the final state for the decoder to start from
+    pub fn flush(&self, writer: &mut BackwardWriter, state: u32) {
+        writer.add_bits(state, self.log);
+    }
+
+    /// Encodes at least 2 symbols with two interleaved states (RFC 8878, 4.2.1.2)
+    pub fn encode_interleaved(&self, symbols: &[u8]) -> Vec<u8> {
+        let n = symbols.len();
+        assert!(n >= 2, "[-] Need at least 2 symbols for an interleaved FSE stream.");
+        let mut writer = BackwardWriter::new();
+
+        let (mut first, mut second, mut index);
+        if n % 2 == 1 {
+            first = self.init_state(symbols[n - 1]);
+            second = self.init_state(symbols[n - 2]);
+            first = self.encode(&mut writer, first, symbols[n - 3]);
+            index = n - 3;
+        } else {
+            second = self.init_state(symbols[n - 1]);
+            first = self.init_state(symbols[n - 2]);
+            index = n - 2;
+        }
+        while index > 0 {
+            second = self.encode(&mut writer, second, symbols[index - 1]);
+            first = self.encode(&mut writer, first, symbols[index - 2]);
+            index -= 2;
+        }
+        self.flush(&mut writer, second);
+        self.flush(&mut writer, first);
+
+        writer.finish()
+    }
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_backward_stream() {
+        let mut writer = BackwardWriter::new();
+        writer.add_bits(0b101, 3);
+        writer.add_bits(0xabcd, 16);
+        writer.add_bits(0, 0);
+        writer.add_bits(0b1, 2);
+        let data = writer.finish();
+
+        let mut reader = BackwardReader::new(&data).unwrap();
+        assert_eq!(reader.read(2), 0b1);
+        assert_eq!(reader.read(16), 0xabcd);
+        assert_eq!(reader.peek(5), 0b10100);
+        assert_eq!(reader.read(3), 0b101);
+        assert_eq!(reader.remaining(), 0);
+        reader.read(1);
+        assert!(reader.overflowed());
+    }
+
+    #[test]
+    fn check_interleaved_round_trip() -> Result<(), Box<dyn Error>> {
+        let symbols: Vec<u8> = (0..101u32).map(|i| [0, 1, 1, 2, 2, 2, 2, 3, 7, 2, 2][(i * 7 % 11) as usize]).collect();
+        let mut counts = [0u32; 8];
+        for &s in &symbols {
+            counts[s as usize] += 1;
+        }
+
+        for log in [5, 6] {
+            let norm = normalize(&counts, log);
+            assert_eq!(norm.iter().map(|&n| n as i32).sum::<i32>(), 1 << log);
+
+            let description = write_distribution(&norm, log);
+            let (read, read_log, used) = read_distribution(&description, 7, 6)?;
+            assert_eq!((&read[..], read_log, used), (&norm[..], log, description.len()));
+
+            for len in [100, 101] {
+                let encoded = EncodeTable::new(&norm, log)?.encode_interleaved(&symbols[..len]);
+                let table = DecodeTable::new(&read, read_log)?;
+                assert_eq!(table.decode_interleaved(&encoded, 255)?, &symbols[..len]);
+                assert_eq!(table.decode_exact(&encoded, len)?, &symbols[..len]);
+                assert!(table.decode_exact(&encoded, len + 1).is_err());
+            }
+        }
+
+        Ok(())
+    }
+}
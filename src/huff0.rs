@@ -0,0 +1,508 @@
+use crate::canonical;
+use crate::fse::{self, BackwardReader, BackwardWriter, DecodeTable, EncodeTable};
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+/// Longest Huff0 code
+pub const MAX_CODE_LEN: u8 = 11;
+/// Biggest accuracy log of the FSE table compressing the weights
+const MAX_WEIGHTS_LOG: u8 = 6;
+/// Literals shorter than this go in a single stream
+const SINGLE_STREAM_LIMIT: usize = 256;
+
+/// Type of a literals section, in the 2 low bits of its header (RFC 8878, 3.1.1.3.1.1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiteralsType {
+    Raw = 0,
+    Rle = 1,
+    Compressed = 2,
+    Treeless = 3,
+}
+
+/// A Huff0 prefix code, described by a weight per symbol (RFC 8878, 4.2.1).
+/// A weight `w > 0` means a `max_bits + 1 - w` bit code, 0 means the symbol is absent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HuffmanTable {
+    weights: Vec<u8>,
+    max_bits: u8,
+}
+
+impl HuffmanTable {
+    /// Builds the optimal table for the given byte frequencies, at least 2 bytes must be present
+    pub fn from_frequencies(freqs: &[u64; 256]) -> Result<Self, Box<dyn Error>> {
+        let lengths = canonical::lengths_from_frequencies(freqs, MAX_CODE_LEN);
+        let max_bits = lengths.iter().copied().max().unwrap_or(0);
+        if lengths.iter().filter(|&&l| l != 0).count() < 2 {
+            return Err(From::from("[-] Huff0 needs at least 2 different symbols."));
+        }
+        let last = lengths.iter().rposition(|&l| l != 0).unwrap();
+        let weights = lengths[..=last].iter().map(|&l| if l == 0 { 0 } else { max_bits + 1 - l }).collect();
+
+        Ok(HuffmanTable { weights, max_bits })
+    }
+
+    /// Completes the weights of all symbols but the last one
+    pub fn from_weights(mut weights: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if weights.iter().any(|&w| w > MAX_CODE_LEN) {
+            return Err(From::from("[-] Invalid Huff0 weight."));
+        }
+        let total: u32 = weights.iter().filter(|&&w| w > 0).map(|&w| 1 << (w - 1)).sum();
+        if total == 0 {
+            return Err(From::from("[-] Huff0 table without symbols."));
+        }
+        // The last weight brings the total to the next power of 2
+        let max_bits = (32 - total.leading_zeros()) as u8;
+        let left = (1u32 << max_bits) - total;
+        if max_bits > MAX_CODE_LEN || !left.is_power_of_two() || weights.len() > 255 {
+            return Err(From::from("[-] Invalid Huff0 weights."));
+        }
+        weights.push(left.trailing_zeros() as u8 + 1);
+
+        Ok(HuffmanTable { weights, max_bits })
+    }
+
+    /// Length of the longest code
+    pub fn max_bits(&self) -> u8 {
+        self.max_bits
+    }
+
+    /// Code length of every byte, 0 for absent ones
+    pub fn lengths(&self) -> [u8; 256] {
+        let mut ret = [0u8; 256];
+        for (s, &w) in self.weights.iter().enumerate() {
+            if w > 0 {
+                ret[s] = self.max_bits + 1 - w;
+            }
+        }
+        ret
+    }
+
+    /// First slot of every symbol in a table of `1 << max_bits` slots: by increasing weight, then value
+    fn slots(&self) -> Vec<(u8, u32)> {
+        let mut order: Vec<usize> = (0..self.weights.len()).filter(|&s| self.weights[s] > 0).collect();
+        order.sort_by_key(|&s| (self.weights[s], s));
+        let mut position = 0u32;
+        order.into_iter()
+            .map(|s| {
+                let start = position;
+                position += 1 << (self.weights[s] - 1);
+                (s as u8, start)
+            })
+            .collect()
+    }
+
+    /// Decoding table: symbol and code length for every `max_bits` bit prefix
+    fn decode_table(&self) -> Vec<(u8, u8)> {
+        let mut table = vec![(0u8, 0u8); 1 << self.max_bits];
+        for (symbol, start) in self.slots() {
+            let weight = self.weights[symbol as usize];
+            let entry = (symbol, self.max_bits + 1 - weight);
+            for slot in &mut table[start as usize..(start + (1 << (weight - 1))) as usize] {
+                *slot = entry;
+            }
+        }
+        table
+    }
+
+    /// Code value and length of every byte
+    fn encode_table(&self) -> [(u32, u8); 256] {
+        let mut table = [(0u32, 0u8); 256];
+        for (symbol, start) in self.slots() {
+            let nb_bits = self.max_bits + 1 - self.weights[symbol as usize];
+            table[symbol as usize] = (start >> (self.max_bits - nb_bits), nb_bits);
+        }
+        table
+    }
+
+    /// Reads a Huffman tree description, returns the table and the number of bytes used
+    pub fn read_description(data: &[u8]) -> Result<(Self, usize), Box<dyn Error>> {
+        let header = *data.first().ok_or("[-] Missing Huff0 tree description.")? as usize;
+        if header >= 128 {
+            // Direct representation: 4 bits per weight, high nibble first
+            let nb = header - 127;
+            let size = nb.div_ceil(2);
+            if data.len() < 1 + size {
+                return Err(From::from("[-] Truncated Huff0 tree description."));
+            }
+            let weights = (0..nb).map(|i| (data[1 + i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0x0f).collect();
+            return Ok((HuffmanTable::from_weights(weights)?, 1 + size));
+        }
+
+        // FSE compressed weights
+        if data.len() < 1 + header {
+            return Err(From::from("[-] Truncated Huff0 tree description."));
+        }
+        let compressed = &data[1..1 + header];
+        let (norm, log, used) = fse::read_distribution(compressed, MAX_CODE_LEN, MAX_WEIGHTS_LOG)?;
+        let weights = DecodeTable::new(&norm, log)?.decode_interleaved(&compressed[used..], 255)?;
+        Ok((HuffmanTable::from_weights(weights)?, 1 + header))
+    }
+
+    /// Writes the tree description, FSE compressed when that is smaller
+    pub fn write_description(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let weights = &self.weights[..self.weights.len() - 1];
+
+        let mut direct = vec![(weights.len() + 127) as u8];
+        for pair in weights.chunks(2) {
+            direct.push((pair[0] << 4) | pair.get(1).copied().unwrap_or(0));
+        }
+
+        match self.compress_weights(weights) {
+            Some(compressed) if compressed.len() < direct.len() || weights.len() > 128 => {
+                let mut ret = vec![compressed.len() as u8];
+                ret.extend(compressed);
+                Ok(ret)
+            }
+            // Over 128 weights can only be sent compressed
+            _ if weights.len() > 128 => Err(From::from("[-] Huff0 weights are not compressible.")),
+            _ => Ok(direct),
+        }
+    }
+
+    fn compress_weights(&self, weights: &[u8]) -> Option<Vec<u8>> {
+        if weights.len() < 2 {
+            return None;
+        }
+        let mut counts = [0u32; MAX_CODE_LEN as usize + 1];
+        for &w in weights {
+            counts[w as usize] += 1;
+        }
+        let last = counts.iter().rposition(|&c| c != 0).unwrap();
+        if counts[..=last].iter().filter(|&&c| c != 0).count() < 2 {
+            return None;
+        }
+        let norm = fse::normalize(&counts[..=last], MAX_WEIGHTS_LOG);
+        // With two weights or more none fills the table, so the states the encoder starts from
+        // read at least one bit and the decoder finds the end of the stream (RFC 8878, 4.2.1.2)
+        let mut ret = fse::write_distribution(&norm, MAX_WEIGHTS_LOG);
+        ret.extend(EncodeTable::new(&norm, MAX_WEIGHTS_LOG).ok()?.encode_interleaved(weights));
+        if ret.len() >= 128 {
+            return None;
+        }
+        Some(ret)
+    }
+
+    /// Decodes `count` bytes from a single Huff0 stream
+    pub fn decode_stream(&self, data: &[u8], count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let table = self.decode_table();
+        let mut reader = BackwardReader::new(data)?;
+        let mut ret = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (symbol, nb_bits) = table[reader.peek(self.max_bits) as usize];
+            reader.consume(nb_bits);
+            ret.push(symbol);
+        }
+        if reader.remaining() != 0 {
+            return Err(From::from("[-] Corrupted Huff0 stream."));
+        }
+        Ok(ret)
+    }
+
+    /// Encodes bytes as a single Huff0 stream
+    pub fn encode_stream(&self, literals: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let table = self.encode_table();
+        let mut writer = BackwardWriter::new();
+        // The decoder reads the last bits written first
+        for &b in literals.iter().rev() {
+            let (code, nb_bits) = table[b as usize];
+            if nb_bits == 0 {
+                return Err(From::from("[-] Byte missing from the Huff0 table."));
+            }
+            writer.add_bits(code, nb_bits);
+        }
+        Ok(writer.finish())
+    }
+
+    /// Decodes `count` bytes from 4 streams behind a 6 bytes jump table
+    pub fn decode_4_streams(&self, data: &[u8], count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        if data.len() < 6 {
+            return Err(From::from("[-] Truncated Huff0 jump table."));
+        }
+        let mut sizes = [0usize; 4];
+        for (i, size) in sizes.iter_mut().take(3).enumerate() {
+            *size = u16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as usize;
+        }
+        let total: usize = 6 + sizes[..3].iter().sum::<usize>();
+        if total > data.len() {
+            return Err(From::from("[-] Invalid Huff0 jump table."));
+        }
+        sizes[3] = data.len() - total;
+
+        // Every stream but the last one regenerates a quarter of the bytes, rounded up
+        let segment = count.div_ceil(4);
+        if 3 * segment > count {
+            return Err(From::from("[-] Too few literals for 4 Huff0 streams."));
+        }
+        let mut ret = Vec::with_capacity(count);
+        let mut offset = 6;
+        for (i, &size) in sizes.iter().enumerate() {
+            let nb = if i == 3 { count - 3 * segment } else { segment };
+            ret.extend(self.decode_stream(&data[offset..offset + size], nb)?);
+            offset += size;
+        }
+        Ok(ret)
+    }
+
+    /// Encodes bytes as 4 streams behind a 6 bytes jump table
+    pub fn encode_4_streams(&self, literals: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let segment = literals.len().div_ceil(4);
+        let mut ret = vec![0u8; 6];
+        for (i, part) in literals.chunks(segment.max(1)).chain(std::iter::repeat(&[][..])).take(4).enumerate() {
+            let stream = self.encode_stream(part)?;
+            if i < 3 {
+                let size = u16::try_from(stream.len()).map_err(|_| "[-] Huff0 stream too big.")?;
+                ret[2 * i..2 * i + 2].copy_from_slice(&size.to_le_bytes());
+            }
+            ret.extend(stream);
+        }
+        Ok(ret)
+    }
+}
+
+/// Decoded literals section
+#[derive(Debug)]
+pub struct Literals {
+    pub data: Vec<u8>,
+    /// Size of the section, header included
+    pub size: usize,
+    /// Table of a Compressed section, for the Treeless sections after it
+    pub table: Option<HuffmanTable>,
+}
+
+/// Reads the little-endian integer of `n` bytes
+fn read_le(data: &[u8], n: usize) -> Result<u64, Box<dyn Error>> {
+    if data.len() < n {
+        return Err(From::from("[-] Truncated literals section header."));
+    }
+    Ok(data[..n].iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+/// Decodes a zstd literals section. `previous` is the last table seen, used by Treeless sections.
+pub fn decode_literals(section: &[u8], previous: Option<&HuffmanTable>) -> Result<Literals, Box<dyn Error>> {
+    let first = *section.first().ok_or("[-] Empty literals section.")?;
+    let size_format = (first >> 2) & 3;
+    let kind = match first & 3 {
+        0 => LiteralsType::Raw,
+        1 => LiteralsType::Rle,
+        2 => LiteralsType::Compressed,
+        _ => LiteralsType::Treeless,
+    };
+
+    if kind == LiteralsType::Raw || kind == LiteralsType::Rle {
+        let (header, regenerated) = match size_format {
+            0 | 2 => (1, (first >> 3) as usize),
+            1 => (2, (read_le(section, 2)? >> 4) as usize),
+            _ => (3, (read_le(section, 3)? >> 4) as usize),
+        };
+        let payload = if kind == LiteralsType::Raw { regenerated } else { 1 };
+        if section.len() < header + payload {
+            return Err(From::from("[-] Truncated literals section."));
+        }
+        let data = match kind {
+            LiteralsType::Raw => section[header..header + regenerated].to_vec(),
+            _ => vec![section[header]; regenerated],
+        };
+        return Ok(Literals { data, size: header + payload, table: None });
+    }
+
+    // Compressed and Treeless: regenerated and compressed sizes share the header bits
+    let (header, bits, four_streams) = match size_format {
+        0 => (3, 10, false),
+        1 => (3, 10, true),
+        2 => (4, 14, true),
+        _ => (5, 18, true),
+    };
+    let value = read_le(section, header)? >> 4;
+    let mask = (1u64 << bits) - 1;
+    let regenerated = (value & mask) as usize;
+    let compressed = ((value >> bits) & mask) as usize;
+    if section.len() < header + compressed {
+        return Err(From::from("[-] Truncated literals section."));
+    }
+    let mut payload = &section[header..header + compressed];
+
+    let table = match kind {
+        LiteralsType::Compressed => {
+            let (table, used) = HuffmanTable::read_description(payload)?;
+            payload = &payload[used..];
+            table
+        }
+        _ => previous.cloned().ok_or("[-] Treeless literals without a previous table.")?,
+    };
+    let data = match four_streams {
+        true => table.decode_4_streams(payload, regenerated)?,
+        false => table.decode_stream(payload, regenerated)?,
+    };
+
+    Ok(Literals { data, size: header + compressed, table: Some(table) })
+}
+
+/// Header of a Raw or RLE section
+fn raw_header(kind: LiteralsType, len: usize) -> Vec<u8> {
+    let kind = kind as u64;
+    let (value, n) = match len {
+        0..=31 => (kind | (len as u64) << 3, 1),
+        32..=4095 => (kind | 1 << 2 | (len as u64) << 4, 2),
+        _ => (kind | 3 << 2 | (len as u64) << 4, 3),
+    };
+    value.to_le_bytes()[..n].to_vec()
+}
+
+/// Encodes literals as the smallest of a Raw, RLE or Compressed zstd literals section
+pub fn encode_literals(literals: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if literals.len() >= 1 << 20 {
+        return Err(From::from("[-] Too many literals for a single section."));
+    }
+    let mut raw = raw_header(LiteralsType::Raw, literals.len());
+    raw.extend_from_slice(literals);
+    if literals.is_empty() {
+        return Ok(raw);
+    }
+    if literals.iter().all(|&b| b == literals[0]) {
+        let mut rle = raw_header(LiteralsType::Rle, literals.len());
+        rle.push(literals[0]);
+        return Ok(rle);
+    }
+
+    let mut freqs = [0u64; 256];
+    for &b in literals {
+        freqs[b as usize] += 1;
+    }
+    let table = HuffmanTable::from_frequencies(&freqs)?;
+    let four_streams = literals.len() >= SINGLE_STREAM_LIMIT;
+    let mut payload = match table.write_description() {
+        Ok(description) => description,
+        Err(_) => return Ok(raw),
+    };
+    payload.extend(match four_streams {
+        true => table.encode_4_streams(literals)?,
+        false => table.encode_stream(literals)?,
+    });
+
+    let largest = literals.len().max(payload.len());
+    let (size_format, header, bits) = match largest {
+        _ if !four_streams && largest < 1 << 10 => (0u64, 3, 10),
+        _ if largest < 1 << 10 => (1, 3, 10),
+        _ if largest < 1 << 14 => (2, 4, 14),
+        _ if largest < 1 << 18 => (3, 5, 18),
+        _ => return Ok(raw),
+    };
+    if !four_streams && size_format != 0 {
+        return Ok(raw);
+    }
+    let value = LiteralsType::Compressed as u64 | size_format << 2 | (literals.len() as u64) << 4 | (payload.len() as u64) << (4 + bits);
+    let mut ret = value.to_le_bytes()[..header].to_vec();
+    ret.extend(payload);
+
+    if ret.len() >= raw.len() {
+        return Ok(raw);
+    }
+    Ok(ret)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds the literals section of the first block of a single-frame zstd file
+    fn first_literals_section(frame: &[u8]) -> &[u8] {
+        assert_eq!(&frame[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        let descriptor = frame[4];
+        let fcs = match descriptor >> 6 {
+            0 => (descriptor >> 5) & 1,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        } as usize;
+        let window = if descriptor & 0x20 == 0 { 1 } else { 0 };
+        let dict_id = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let block = 5 + window + dict_id + fcs;
+        // Compressed block
+        assert_eq!((frame[block] >> 1) & 3, 2);
+        &frame[block + 3..]
+    }
+
+    #[test]
+    fn check_zstd_vectors() -> Result<(), Box<dyn Error>> {
+        // Files without any repeated 3 bytes sequence, so that zstd keeps every byte as a literal
+        for name in ["huff0_single", "huff0_four"] {
+            let original = std::fs::read(format!("./data/{}.bin", name))?;
+            let frame = std::fs::read(format!("./data/{}.bin.zst", name))?;
+
+            let literals = decode_literals(first_literals_section(&frame), None)?;
+            assert_eq!(literals.data, original);
+            assert!(literals.table.is_some());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_literals_round_trip() -> Result<(), Box<dyn Error>> {
+        let file_lazydog = std::fs::read("./data/lazy_dog.txt")?;
+        let skewed: Vec<u8> = (0..5000u32).map(|i| (i * i % 97 % 13) as u8 + b'a').collect();
+        let wide: Vec<u8> = (0..3000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8 / 2 + (i % 3) as u8).collect();
+
+        for literals in [&file_lazydog[..], &skewed, &wide, b"aaaaaaaa", b""] {
+            let section = encode_literals(literals)?;
+            let decoded = decode_literals(&section, None)?;
+            assert_eq!(decoded.data, literals);
+            assert_eq!(decoded.size, section.len());
+        }
+
+        // Over 128 symbols, the weights have to be FSE compressed
+        let mut freqs = [0u64; 256];
+        for &b in &wide {
+            freqs[b as usize] += 1;
+        }
+        assert!(HuffmanTable::from_frequencies(&freqs)?.write_description()?[0] < 128);
+
+        // Treeless sections reuse the previous table
+        let mut freqs = [0u64; 256];
+        for &b in &skewed {
+            freqs[b as usize] += 1;
+        }
+        let table = HuffmanTable::from_frequencies(&freqs)?;
+        let description = table.write_description()?;
+        let (read, used) = HuffmanTable::read_description(&description)?;
+        assert_eq!(read, table);
+        assert_eq!(used, description.len());
+        let streams = table.encode_4_streams(&skewed[..1000])?;
+        let value = LiteralsType::Treeless as u64 | 2 << 2 | 1000 << 4 | (streams.len() as u64) << 18;
+        let mut section = value.to_le_bytes()[..4].to_vec();
+        section.extend(streams);
+        assert_eq!(decode_literals(&section, Some(&table))?.data, &skewed[..1000]);
+        assert!(decode_literals(&section, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_compressed_weights() -> Result<(), Box<dyn Error>> {
+        // A weight over half of the FSE table has states that read no bits, the stream must still
+        // end on the last weight. Odd and even counts end on different states.
+        let table = HuffmanTable::from_weights(vec![1])?;
+        for count in [5usize, 6, 33, 34, 128, 201, 255] {
+            for dominant in [1u8, 2, 7] {
+                let weights: Vec<u8> = (0..count).map(|i| if i % 9 == 4 { dominant + 1 } else { dominant }).collect();
+                let compressed = table.compress_weights(&weights).ok_or("weights not compressible")?;
+                let (norm, log, used) = fse::read_distribution(&compressed, MAX_CODE_LEN, MAX_WEIGHTS_LOG)?;
+                assert_eq!(DecodeTable::new(&norm, log)?.decode_interleaved(&compressed[used..], 255)?, weights);
+            }
+        }
+
+        // Sent compressed, with the last weight left out
+        let mut freqs = [0u64; 256];
+        for (i, f) in freqs.iter_mut().enumerate().take(200) {
+            *f = if i % 10 == 0 { 1 } else { 40 };
+        }
+        let table = HuffmanTable::from_frequencies(&freqs)?;
+        let description = table.write_description()?;
+        assert!(description[0] < 128);
+        assert_eq!(HuffmanTable::read_description(&description)?, (table, description.len()));
+
+        Ok(())
+    }
+}
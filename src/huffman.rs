@@ -1,18 +1,70 @@
+use huffman::bzip2;
+use huffman::canonical::CanonicalCode;
+use huffman::code;
+use huffman::crc32::Crc32;
+pub use huffman::dictionary::Dictionary;
 use huffman::file_bin;
+use huffman::file_bin::BitRead;
+use huffman::file_bin::BitWrite;
+use huffman::fse;
+use huffman::range;
+use huffman::transform;
+use huffman::utf8;
+use huffman::words;
+
+use log::{debug, info, trace, warn};
 
 use std::cmp::Ordering;
 use std::error::Error;
 use std::fmt;
+use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::vec::Vec;
 
 /// Size of the chunks while reading input files
 const CHUNK_SIZE: usize = 4096;
-/// Self explanatory
-pub static VERBOSE: bool = true;
+/// Size of the blocks compressed with their own tree
+const BLOCK_SIZE: usize = 1 << 20;
+/// Longest transformed block: escaped move-to-front positions take 2 bytes
+const MAX_CODED_SIZE: usize = 2 * BLOCK_SIZE;
+/// First bytes of a compressed file, followed by the format version and the header flags
+pub const MAGIC: &[u8; 4] = b"HUFF";
+const VERSION: u8 = 3;
+/// Last version without header flags, still read
+const VERSION_NO_HEADER: u8 = 2;
+/// Header flags: the original name, permissions and modification time follow, in this order
+const HEADER_NAME: u8 = 1;
+const HEADER_MODE: u8 = 2;
+const HEADER_MTIME: u8 = 4;
+/// Header flag: the end block is followed by the size and CRC-32 of the data
+const HEADER_CHECKSUM: u8 = 8;
+/// Block kinds
+const BLOCK_END: u8 = 0;
+const BLOCK_HUFFMAN: u8 = 1;
+const BLOCK_MULTI: u8 = 2;
+const BLOCK_ANS: u8 = 3;
+const BLOCK_RANGE: u8 = 4;
+const BLOCK_WORDS: u8 = 5;
+const BLOCK_UTF8: u8 = 6;
+const BLOCK_DICTIONARY: u8 = 7;
+const BLOCK_STORED: u8 = 8;
+/// Size of a block header without transform
+const BLOCK_HEADER_SIZE: u64 = 6;
+/// Block flag: the block went through the BWT + MTF + RLE transform
+const FLAG_TRANSFORM: u8 = 1;
+/// Size of the segments that each pick one of the tables of a block
+const SEGMENT_SIZE: usize = 1024;
+/// Maximum number of tables of a block
+pub const MAX_TABLES: usize = 16;
+/// Rounds of reassigning segments to tables and rebuilding the tables
+const REFINE_ITERATIONS: usize = 4;
+/// Accuracy log of the ANS tables: probabilities are multiples of 1/4096
+const ANS_LOG: u8 = 12;
 
 // Tree Node: Contains a byte and possible (e.g Options) leafs
 #[derive (Eq)]
@@ -111,64 +163,735 @@ impl fmt::Display for LNode {
     }
 }
 
+/// Code of every byte, as generated by gen_codes()
+pub type Codes = [Option<[Option<bool>; 30]>; 256];
+
+/// Entropy coder of the blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entropy {
+    /// Prefix codes from Huffman trees, at least 1 bit per byte
+    Huffman,
+    /// Table based asymmetric numeral systems (tANS/FSE), fractional bits per byte
+    Ans,
+    /// Adaptive binary range coder, fractional bits per byte and follows the statistics along the block
+    Range,
+}
+
+/// Symbols the blocks are coded with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alphabet {
+    /// Bytes
+    Bytes,
+    /// Words and separators of text, with the vocabulary stored in the block.
+    /// The transform, tables and entropy settings do not apply.
+    Words,
+    /// Unicode scalar values of UTF-8 text, invalid bytes escaped, with the alphabet stored in the block.
+    /// The transform, tables and entropy settings do not apply.
+    Utf8,
+}
+
+/// Compression settings
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Run every block through the BWT, move-to-front and zero run length transform first
+    pub transform: bool,
+    /// Maximum number of Huffman tables per block, each segment of a block selects one of them
+    pub tables: usize,
+    /// Entropy coder, blocks that ANS cannot code (a single distinct byte) fall back to Huffman
+    pub entropy: Entropy,
+    /// Symbols to code
+    pub alphabet: Alphabet,
+    /// Shared code used instead of a tree per block, needed again to decompress.
+    /// The other settings do not apply.
+    pub dictionary: Option<Dictionary>,
+    /// Called while compressing or decompressing, after each block
+    pub progress: Option<Progress>,
+    /// Checked before each block, the output is removed when cancelled
+    pub cancel: Option<CancelToken>,
+    /// Original file recorded in the header, its fields left to None are not
+    pub origin: Option<Origin>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            transform: false,
+            tables: 1,
+            entropy: Entropy::Huffman,
+            alphabet: Alphabet::Bytes,
+            dictionary: None,
+            progress: None,
+            cancel: None,
+            origin: None,
+        }
+    }
+}
+
+/// Progress hook: called with the bytes read and written so far
+#[derive(Clone)]
+pub struct Progress {
+    interval: u64,
+    callback: Arc<dyn Fn(u64, u64) + Send + Sync>,
+}
+
+impl Progress {
+    /// The callback gets called once at least `interval` more bytes were read, and once at the end.
+    /// Blocks are reported whole, so intervals under the block size call it after every block.
+    pub fn new<F: Fn(u64, u64) + Send + Sync + 'static>(interval: u64, callback: F) -> Self {
+        Progress { interval, callback: Arc::new(callback) }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress").field("interval", &self.interval).finish()
+    }
+}
+
+/// Shared flag to stop a compression or decompression from another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+}
+
+/// Error of a compression or decompression stopped by its CancelToken
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[-] Cancelled.")
+    }
+}
+
+impl Error for Cancelled {}
+
+fn is_cancelled(options: &Options) -> bool {
+    options.cancel.as_ref().is_some_and(|token| token.is_cancelled())
+}
+
+/// Removes the partial output of a failed or cancelled run, which must be closed already
+fn discard(path_out: &str, e: Box<dyn Error>) -> Box<dyn Error> {
+    match std::fs::remove_file(path_out) {
+        Ok(()) => info!("Removed [{}].", path_out),
+        Err(e) => warn!("Could not remove [{}]: {}", path_out, e),
+    }
+    e
+}
+
+/// Original file of a compressed one, kept in the optional header extension
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// File name, without its directories
+    pub name: Option<String>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    /// Modification time, seconds since the Unix epoch
+    pub mtime: Option<i64>,
+}
+
+impl Origin {
+    /// Name, permissions and modification time of a file
+    pub fn of(path: &std::path::Path) -> Result<Self, Box<dyn Error>> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Origin {
+            name: path.file_name().and_then(|name| name.to_str()).map(String::from),
+            mode: Some(mode(&metadata)),
+            mtime: Some(mtime(&metadata)),
+        })
+    }
+
+    /// Gives the permissions and modification time back to a decompressed file
+    pub fn restore(&self, path: &std::path::Path) -> Result<(), Box<dyn Error>> {
+        if let Some(mtime) = self.mtime {
+            set_mtime(path, mtime)?;
+        }
+        if let Some(mode) = self.mode {
+            set_mode(path, mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// Permission bits of a file
+#[cfg(unix)]
+pub fn mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+pub fn mode(metadata: &std::fs::Metadata) -> u32 {
+    match metadata.permissions().readonly() {
+        true => 0o444,
+        false => 0o644,
+    }
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &std::path::Path, mode: u32) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(path: &std::path::Path, mode: u32) -> Result<(), Box<dyn Error>> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    std::fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// Modification time of a file in seconds since the Unix epoch, 0 when unknown
+pub fn mtime(metadata: &std::fs::Metadata) -> i64 {
+    metadata.modified().ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+
+/// Sets the modification time of a file, in seconds since the Unix epoch
+pub fn set_mtime(path: &std::path::Path, mtime: i64) -> Result<(), Box<dyn Error>> {
+    let time = match mtime >= 0 {
+        true => std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64),
+        false => std::time::UNIX_EPOCH - std::time::Duration::from_secs(mtime.unsigned_abs()),
+    };
+    std::fs::File::options().write(true).open(path)?.set_modified(time)?;
+    Ok(())
+}
+
+/// Calls the progress hook of the options when the interval is reached
+struct Ticker<'a> {
+    progress: Option<&'a Progress>,
+    next: u64,
+}
+
+impl<'a> Ticker<'a> {
+    fn new(options: &'a Options) -> Self {
+        let progress = options.progress.as_ref();
+        Ticker { progress, next: progress.map_or(0, |p| p.interval) }
+    }
+
+    fn update(&mut self, bytes_in: u64, bytes_out: u64) {
+        if let Some(progress) = self.progress {
+            if bytes_in >= self.next {
+                (progress.callback)(bytes_in, bytes_out);
+                self.next = bytes_in + progress.interval.max(1);
+            }
+        }
+    }
+
+    fn finish(&self, bytes_in: u64, bytes_out: u64) {
+        if let Some(progress) = self.progress {
+            (progress.callback)(bytes_in, bytes_out);
+        }
+    }
+}
+
+/// What compress() or decompress() did
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// Bytes read
+    pub size_in: u64,
+    /// Bytes written
+    pub size_out: u64,
+    /// Number of blocks, 0 for files without blocks
+    pub blocks: usize,
+    /// Depth of the deepest Huffman tree, 0 when no block used one
+    pub tree_depth: usize,
+    /// CRC-32 of the uncompressed data
+    pub checksum: u32,
+    /// Format of the compressed file
+    pub format: Format,
+    /// Content of each block, only filled when decompressing
+    pub block_list: Vec<BlockInfo>,
+    /// Original file found in the header, only filled when decompressing
+    pub origin: Option<Origin>,
+    /// The data matched the size and checksum stored with it, only set when decompressing
+    pub verified: bool,
+}
+
+/// Formats read by decompress()
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Magic, version and blocks
+    #[default]
+    Blocks,
+    /// Size, tree and data of the first versions
+    Legacy,
+    Bzip2,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Blocks => write!(f, "huffman v{}", VERSION),
+            Format::Legacy => write!(f, "huffman legacy"),
+            Format::Bzip2 => write!(f, "bzip2"),
+        }
+    }
+}
+
+/// What a block of a compressed file holds
+#[derive(Clone, Debug, Default)]
+pub struct BlockInfo {
+    /// Block kind as written in its header
+    pub kind: u8,
+    /// Bytes once decompressed
+    pub size: usize,
+    /// Bytes taken in the compressed file, header included
+    pub compressed: u64,
+    /// Went through the BWT + MTF + RLE transform
+    pub transform: bool,
+    /// Codes of the bytes, one table per Huffman tree of the block
+    pub tables: Vec<Vec<(u8, String)>>,
+}
+
+impl BlockInfo {
+    /// Name of the block kind
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            BLOCK_HUFFMAN => "huffman",
+            BLOCK_MULTI => "multi",
+            BLOCK_ANS => "ans",
+            BLOCK_RANGE => "range",
+            BLOCK_WORDS => "words",
+            BLOCK_UTF8 => "utf8",
+            BLOCK_DICTIONARY => "dictionary",
+            BLOCK_STORED => "stored",
+            _ => "unknown",
+        }
+    }
+
+    /// JSON object of the block, with its code tables if asked to
+    pub fn to_json(&self, tables: bool) -> String {
+        let mut ret = format!("{{\"kind\":{},\"size\":{},\"compressed\":{},\"transform\":{}",
+                              json_string(self.name()), self.size, self.compressed, self.transform);
+        if tables {
+            let tables: Vec<String> = self.tables.iter().map(|table| {
+                let codes: Vec<String> = table.iter().map(|(byte, code)| format!("{{\"byte\":{},\"code\":\"{}\"}}", byte, code)).collect();
+                format!("[{}]", codes.join(","))
+            }).collect();
+            ret.push_str(&format!(",\"tables\":[{}]", tables.join(",")));
+        }
+        ret.push('}');
+        ret
+    }
+}
+
+impl Report {
+    /// Compressed size over uncompressed size
+    pub fn ratio(&self, compress: bool) -> f64 {
+        let (compressed, uncompressed) = if compress { (self.size_out, self.size_in) } else { (self.size_in, self.size_out) };
+        match uncompressed {
+            0 => 0.0,
+            n => compressed as f64 / n as f64,
+        }
+    }
+
+    pub fn to_json(&self, compress: bool, path_in: &str, path_out: &str, seconds: f64) -> String {
+        format!(concat!("{{\"mode\":{},\"input\":{},\"output\":{},\"size_in\":{},\"size_out\":{},\"ratio\":{},",
+                        "\"seconds\":{},\"checksum\":\"{:08x}\",\"tree_depth\":{},\"blocks\":{}}}"),
+                json_string(if compress { "compress" } else { "decompress" }), json_string(path_in), json_string(path_out),
+                self.size_in, self.size_out, self.ratio(compress), seconds, self.checksum, self.tree_depth, self.blocks)
+    }
+}
+
+/// Escapes a string for JSON
+pub fn json_string(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len() + 2);
+    ret.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Depth of the deepest leaf
+fn tree_depth(tree: &TNode) -> usize {
+    match (&tree.left, &tree.right) {
+        (Some(left), Some(right)) => 1 + tree_depth(left).max(tree_depth(right)),
+        _ => 0,
+    }
+}
+
+/// Code of every byte of the tree, as a string of 0 and 1
+pub fn code_table(tree: &TNode) -> Vec<(u8, String)> {
+    let mut codes = [None; 256];
+    gen_codes(Some(tree), [None; 30], 0, &mut codes);
+    codes.iter().enumerate()
+        .filter_map(|(byte, code)| code.map(|code| (byte as u8, code.iter().map_while(|bit| bit.map(|b| if b { '1' } else { '0' })).collect())))
+        .collect()
+}
+
+/// Tree as it is written in the file: 1 for a branch, 0 and the byte for a leaf
+fn tree_string(tree: &TNode) -> String {
+    match (tree.byte, &tree.left, &tree.right) {
+        (Some(byte), _, _) => format!("0{}", byte),
+        (None, Some(left), Some(right)) => format!("1{}{}", tree_string(left), tree_string(right)),
+        _ => String::new(),
+    }
+}
+
 /// Main compress() function
-pub fn compress(path_in: &String, path_out: &String) -> Result<(), Box<dyn Error>> {
+pub fn compress(path_in: &String, path_out: &String, options: &Options) -> Result<Report, Box<dyn Error>> {
 
     // TODO: Move this arg logic to main.rs
-    println!("[+] HUFFMAN COMPRESS");
-    println!("[+] In file: [{}]", path_in);
-    println!("[+] Out file: [{}]", path_out);
+    info!("HUFFMAN COMPRESS");
+    info!("In file: [{}]", path_in);
+    info!("Out file: [{}]", path_out);
 
-    // Open the file and use a bufreader for it
+    // Open the file and use a bufwriter for the output
     let file_in = std::fs::File::open(path_in)?;
+    let file_out = std::io::BufWriter::new(std::fs::File::create(path_out)?);
+    compress_stream(file_in, file_out, path_out, options).map_err(|e| discard(path_out, e))
+}
+
+/// Compresses a stream, `name` only names the output in messages.
+/// Blocks are coded in memory before being written, so the output does not need to be seekable.
+pub fn compress_stream<R: Read, W: Write>(mut reader: R, writer: W, name: &str, options: &Options) -> Result<Report, Box<dyn Error>> {
+    if options.tables == 0 || options.tables > MAX_TABLES {
+        return Err(From::from(format!("[-] The number of tables must be between 1 and {}.", MAX_TABLES)));
+    }
+
+    // Binary output
+    let mut file_out = file_bin::BinFile::new(writer, name);
+    // Write header (magic, format version and original file)
+    file_out.write_bytes(MAGIC)?;
+    file_out.write_byte(VERSION)?;
+    write_header(&mut file_out, options.origin.as_ref())?;
+    info!("Successfully wrote header.");
+
+    // Compress the file block by block, each with its own tree
+    let mut total = 0;
+    let mut report = Report::default();
+    let mut crc = Crc32::new();
+    let mut ticker = Ticker::new(options);
+    loop {
+        if is_cancelled(options) {
+            return Err(Box::new(Cancelled));
+        }
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        let n = reader.by_ref().take(BLOCK_SIZE as u64).read_to_end(&mut block)?;
+        if n == 0 { break; }
+        crc.update(&block);
+        report.blocks += 1;
+        let mut coded = file_bin::BinFile::new(Vec::new(), name);
+        let depth = report.tree_depth;
+        compress_block(&block, &mut coded, options, &mut report)?;
+        coded.flush()?;
+        // Blocks that would grow are stored as they are
+        if coded.tell() > BLOCK_HEADER_SIZE + n as u64 {
+            report.tree_depth = depth;
+            write_block_header(&mut file_out, BLOCK_STORED, n, None, n)?;
+            file_out.write_bytes(&block)?;
+            info!("Stored [{}] incompressible bytes.", n);
+        } else {
+            file_out.write_bytes(&coded.into_inner())?;
+        }
+        total += n;
+        ticker.update(total as u64, file_out.tell());
+        if n < BLOCK_SIZE { break; }
+    }
+    file_out.write_byte(BLOCK_END)?;
+    file_out.write_bytes(&(total as u64).to_le_bytes())?;
+    file_out.write_bytes(&crc.value().to_le_bytes())?;
+    info!("Finished writing compressed file: [{}] bytes in.", total);
+
+    report.size_in = total as u64;
+    report.size_out = file_out.tell();
+    report.checksum = crc.value();
+    file_out.into_inner().flush()?;
+    ticker.finish(report.size_in, report.size_out);
+    Ok(report)
+}
+
+/// Compresses a single block: header, Huffman tree and data
+fn compress_block<F: Write>(block: &[u8], file_out: &mut file_bin::BinFile<F>, options: &Options, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    if let Some(dictionary) = &options.dictionary {
+        debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+        write_block_header(file_out, BLOCK_DICTIONARY, block.len(), None, block.len())?;
+        file_out.write_bytes(&dictionary.id().to_le_bytes())?;
+        dictionary.code().encode(block.iter().copied(), file_out)?;
+        file_out.flush()?;
+        info!("Successfully wrote block coded with dictionary [{:08x}].", dictionary.id());
+        return Ok(());
+    }
+    if options.alphabet == Alphabet::Words {
+        // The header is byte aligned, the token stream is the same written after it
+        let mut payload = file_bin::BitWriter::new();
+        if words::encode(block, &mut payload)? {
+            debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+            write_block_header(file_out, BLOCK_WORDS, block.len(), None, block.len())?;
+            file_out.write_bytes(&payload.into_bytes())?;
+            info!("Successfully wrote word coded block.");
+            return Ok(());
+        }
+        info!("More than [{}] distinct words, coding bytes instead.", words::MAX_VOCABULARY);
+    }
+    if options.alphabet == Alphabet::Utf8 {
+        let mut payload = file_bin::BitWriter::new();
+        if utf8::encode(block, &mut payload)? {
+            debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+            write_block_header(file_out, BLOCK_UTF8, block.len(), None, block.len())?;
+            file_out.write_bytes(&payload.into_bytes())?;
+            info!("Successfully wrote code point coded block.");
+            return Ok(());
+        }
+        info!("More than [{}] distinct code points, coding bytes instead.", utf8::MAX_ALPHABET);
+    }
+    // Transform the block if asked to
+    let (data, origin) = match options.transform {
+        true => {
+            let (data, origin) = transform::encode(block);
+            (data, Some(origin))
+        },
+        false => (block.to_vec(), None),
+    };
+    if options.entropy == Entropy::Ans {
+        if let Some(payload) = ans_encode(&data)? {
+            debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+            write_block_header(file_out, BLOCK_ANS, block.len(), origin, data.len())?;
+            file_out.write_bytes(&(payload.len() as u32).to_le_bytes())?;
+            file_out.write_bytes(&payload)?;
+            info!("Successfully wrote [{}] bytes of ANS data.", payload.len());
+            return Ok(());
+        }
+    }
+    if options.entropy == Entropy::Range {
+        let payload = range::compress(&data);
+        debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+        write_block_header(file_out, BLOCK_RANGE, block.len(), origin, data.len())?;
+        file_out.write_bytes(&(payload.len() as u32).to_le_bytes())?;
+        file_out.write_bytes(&payload)?;
+        info!("Successfully wrote [{}] bytes of range coded data.", payload.len());
+        return Ok(());
+    }
+    // Segments with different statistics get their own tables
+    let segments = data.len().div_ceil(SEGMENT_SIZE);
+    if options.tables > 1 && segments > 1 {
+        return compress_segments(block.len(), &data, origin, file_out, options.tables, report);
+    }
+    let mut input = Cursor::new(&data);
 
     // Get expected List length
-    let nb = count_diff_chars(&file_in)?;
-    println!("[+] Number of different bytes: {}", nb);
+    let nb = count_diff_chars(&mut input)?;
+    info!("Number of different bytes: {}", nb);
 
     // Huffman List
-    let mut huffman_list = build_list(&file_in)?;
-    if huffman_list.len() == 0 {
-        panic!("[-] Cannot compress an empty file.");
-    } else if huffman_list.len() != nb {
+    let mut huffman_list = build_list(&mut input)?;
+    if huffman_list.len() != nb {
         panic!("[-] There was an error while building the Huffman List.");
     } else {
-        println!("[+] Successfully built Huffman List for [{}] different characters.", huffman_list.len());
+        info!("Successfully built Huffman List for [{}] different characters.", huffman_list.len());
     }
 
     // Huffman Tree
-    let (huffman_tree, file_size) = build_tree(&mut huffman_list)?;
-    if file_size as u64 != file_in.metadata().unwrap().len() {
+    let (huffman_tree, size) = build_tree(&mut huffman_list)?;
+    if size != data.len() {
         panic!("[-] There was an error while building the Huffman Tree.");
     } else {
-        println!("[+] Successfully built tree for [{}] bytes", file_size);
+        info!("Successfully built tree for [{}] bytes", size);
     }
 
     // Huffman Codes
     let mut huffman_codes = [None; 256];
     gen_codes(Some(&huffman_tree), [None; 30], 0, &mut huffman_codes);
-
-    // Binary output file
-    let mut file_out = file_bin::BinFile::create(&path_out)?;
-    if VERBOSE {
-        println!("[=] [{}] [SIZE]> {} bytes", path_out, file_size);
-    }
-    // Write header (original file size) and Huffman Tree
-    file_out.write_bytes(&file_size.to_le_bytes())?;
-    println!("[+] Successfully wrote header.");
-    if VERBOSE { print!("[=] [{}] [TREE]> ", path_out); }
-    write_tree(&mut file_out, Some(&huffman_tree))?;
-    println!();
-    println!("[+] Successfully wrote tree.");
-    // Write the compressed data
-    compress_file(&file_in, &mut file_out, &huffman_codes)?;
+    report.tree_depth = report.tree_depth.max(tree_depth(&huffman_tree));
+
+    debug!("[{}] [SIZE]> {} bytes", file_out.path, block.len());
+    write_block_header(file_out, BLOCK_HUFFMAN, block.len(), origin, data.len())?;
+    trace!("[{}] [TREE]> {}", file_out.path, tree_string(&huffman_tree));
+    write_tree(file_out, Some(&huffman_tree))?;
+    // Write the compressed data, blocks end on a byte boundary
+    compress_file(&mut input, file_out, &huffman_codes)?;
     file_out.flush()?;
-    println!();
-    println!("[+] Finished writing compressed file.");
 
     Ok(())
 }
 
+/// Writes a block header: kind, flags, size, and for transformed blocks the BWT origin and coded size
+fn write_block_header<F: Write>(file_out: &mut file_bin::BinFile<F>, kind: u8, size: usize, origin: Option<usize>, coded: usize) -> Result<(), Box<dyn Error>> {
+    file_out.write_byte(kind)?;
+    file_out.write_byte(if origin.is_some() { FLAG_TRANSFORM } else { 0 })?;
+    file_out.write_bytes(&(size as u32).to_le_bytes())?;
+    if let Some(origin) = origin {
+        file_out.write_bytes(&(origin as u32).to_le_bytes())?;
+        file_out.write_bytes(&(coded as u32).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Compresses a block with several tables: header, selectors, Huffman trees and data
+fn compress_segments<F: Write>(size: usize, data: &[u8], origin: Option<usize>, file_out: &mut file_bin::BinFile<F>, nb: usize, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let (selectors, counts) = cluster_segments(data, nb);
+    let tables = build_tables(&counts)?;
+    for (tree, _) in &tables {
+        report.tree_depth = report.tree_depth.max(tree_depth(tree));
+    }
+    info!("Successfully built [{}] tables for [{}] segments", tables.len(), selectors.len());
+
+    debug!("[{}] [SIZE]> {} bytes", file_out.path, size);
+    write_block_header(file_out, BLOCK_MULTI, size, origin, data.len())?;
+    // Number of tables then the table of every segment
+    file_out.write_byte(tables.len() as u8)?;
+    let bits = selector_bits(tables.len());
+    for &selector in &selectors {
+        file_out.write_bits(selector as u32, bits)?;
+    }
+    for (tree, _) in &tables {
+        trace!("[{}] [TREE]> {}", file_out.path, tree_string(tree));
+        write_tree(file_out, Some(tree))?;
+    }
+    // Write the compressed data, blocks end on a byte boundary
+    for (segment, &selector) in data.chunks(SEGMENT_SIZE).zip(selectors.iter()) {
+        compress_file(segment, file_out, &tables[selector as usize].1)?;
+    }
+    file_out.flush()?;
+    
+    Ok(())
+}
+
+/// Counts every byte of the data
+fn count_bytes(data: &[u8]) -> [usize; 256] {
+    let mut count = [0usize; 256];
+    for &x in data {
+        count[x as usize] += 1;
+    }
+    count
+}
+
+/// ANS codes the data: table description then the interleaved stream.
+/// Returns None when the data has less than 2 distinct bytes.
+fn ans_encode(data: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let count = count_bytes(data);
+    if count.iter().filter(|&&c| c != 0).count() < 2 {
+        return Ok(None);
+    }
+    let last = count.iter().rposition(|&c| c != 0).unwrap();
+    let counts: Vec<u32> = count[..=last].iter().map(|&c| c as u32).collect();
+    let norm = fse::normalize(&counts, ANS_LOG);
+
+    let mut ret = fse::write_distribution(&norm, ANS_LOG);
+    ret.extend(fse::EncodeTable::new(&norm, ANS_LOG)?.encode_interleaved(data));
+    Ok(Some(ret))
+}
+
+/// Largest payload ans_encode() writes for `size` bytes: the table takes less than 2 bytes per
+/// symbol, then every byte and the two final states take at most ANS_LOG bits
+fn ans_max_size(size: usize) -> usize {
+    2 * 256 + (size + 2) * ANS_LOG as usize / 8 + 2
+}
+
+/// Decodes `size` bytes of data written by ans_encode()
+fn ans_decode(payload: &[u8], size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (norm, log, used) = fse::read_distribution(payload, 255, ANS_LOG)?;
+    fse::DecodeTable::new(&norm, log)?.decode_exact(&payload[used..], size)
+}
+
+/// Number of bits of a selector among `nb` tables
+fn selector_bits(nb: usize) -> u8 {
+    (usize::BITS - nb.saturating_sub(1).leading_zeros()) as u8
+}
+
+/// Splits the data in segments and groups them into at most `nb` tables: starting from contiguous
+/// runs of segments, every segment moves to the table coding it in the fewest bits, then the tables
+/// are rebuilt from their segments.
+/// Returns the table of every segment and the byte counts of every table.
+fn cluster_segments(data: &[u8], nb: usize) -> (Vec<u8>, Vec<[usize; 256]>) {
+    let segments: Vec<[usize; 256]> = data.chunks(SEGMENT_SIZE).map(count_bytes).collect();
+    let nb = nb.min(segments.len()).max(1);
+    let mut selectors: Vec<u8> = (0..segments.len()).map(|i| (i * nb / segments.len()) as u8).collect();
+
+    let mut counts = table_counts(&segments, &mut selectors);
+    for _ in 0..REFINE_ITERATIONS {
+        let lengths: Vec<[Option<usize>; 256]> = counts.iter().map(code_lengths).collect();
+        for (segment, selector) in segments.iter().zip(selectors.iter_mut()) {
+            let mut best = usize::MAX;
+            for (i, table) in lengths.iter().enumerate() {
+                let cost = segment.iter()
+                    .zip(table.iter())
+                    .filter(|(&c, _)| c != 0)
+                    .try_fold(0usize, |acc, (&c, &len)| len.map(|len| acc + c * len));
+                if let Some(cost) = cost {
+                    if cost < best {
+                        best = cost;
+                        *selector = i as u8;
+                    }
+                }
+            }
+        }
+        counts = table_counts(&segments, &mut selectors);
+    }
+
+    (selectors, counts)
+}
+
+/// Sums the byte counts of the segments of every table, unused tables are dropped and the selectors renumbered
+fn table_counts(segments: &[[usize; 256]], selectors: &mut [u8]) -> Vec<[usize; 256]> {
+    let mut counts: Vec<[usize; 256]> = Vec::new();
+    let mut renumber = [None; 256];
+    for (segment, selector) in segments.iter().zip(selectors.iter_mut()) {
+        let table = *renumber[*selector as usize].get_or_insert_with(|| {
+            counts.push([0usize; 256]);
+            counts.len() - 1
+        });
+        *selector = table as u8;
+        for (total, &c) in counts[table].iter_mut().zip(segment.iter()) {
+            *total += c;
+        }
+    }
+    counts
+}
+
+/// Code length of every byte of the Huffman tree built from these counts, None for missing bytes
+fn code_lengths(count: &[usize; 256]) -> [Option<usize>; 256] {
+    let mut lengths = [None; 256];
+    let mut list = list_from_counts(count);
+    if list.is_empty() {
+        return lengths;
+    }
+    let (tree, _) = build_tree(&mut list).unwrap();
+    let mut codes = [None; 256];
+    gen_codes(Some(&tree), [None; 30], 0, &mut codes);
+    for (len, code) in lengths.iter_mut().zip(codes.iter()) {
+        *len = code.map(|c| c.iter().take_while(|bit| bit.is_some()).count());
+    }
+    lengths
+}
+
+/// Builds the Huffman tree and codes of every table from its byte counts
+pub fn build_tables(counts: &[[usize; 256]]) -> Result<Vec<(TNode, Codes)>, Box<dyn Error>> {
+    let mut ret = Vec::with_capacity(counts.len());
+    for count in counts {
+        let (tree, _) = build_tree(&mut list_from_counts(count))?;
+        let mut codes = [None; 256];
+        gen_codes(Some(&tree), [None; 30], 0, &mut codes);
+        ret.push((tree, codes));
+    }
+    Ok(ret)
+}
+
 /// Writes the given Huffman tree to the given file.
 /// ⚠ This function is recursive.
 ///
@@ -179,20 +902,18 @@ pub fn compress(path_in: &String, path_out: &String) -> Result<(), Box<dyn Error
 ///
 /// Note: This function should usually be called on the head of a Huffman Tree.
 ///
-pub fn write_tree(binfile: &mut file_bin::BinFile, tree: Option<&TNode>) -> Result<(), Box<dyn Error>> {
+pub fn write_tree<F: Write>(binfile: &mut file_bin::BinFile<F>, tree: Option<&TNode>) -> Result<(), Box<dyn Error>> {
 
     match tree {
         None => Ok(()),
         Some(node) => {
-            if node.byte.is_some() {
-                if VERBOSE { print!("0{}",node.byte.unwrap()); }
+            if let Some(byte) = node.byte {
                 binfile.write_bit(false)?;
-                binfile.write_byte(node.byte.unwrap())?;
+                binfile.write_byte(byte)?;
             } else {
-                if VERBOSE { print!("1"); }
                 binfile.write_bit(true)?;
-                write_tree(binfile, Some(&*node.left.as_ref().unwrap()))?;
-                write_tree(binfile, Some(&*node.right.as_ref().unwrap()))?;
+                write_tree(binfile, Some(node.left.as_ref().unwrap()))?;
+                write_tree(binfile, Some(node.right.as_ref().unwrap()))?;
             }
 
             Ok(())
@@ -200,37 +921,44 @@ pub fn write_tree(binfile: &mut file_bin::BinFile, tree: Option<&TNode>) -> Resu
     }
 }
 
-pub fn read_tree(binfile: &mut file_bin::BinFile) -> Result<Option<Box<TNode>>, Box<dyn Error>> {
+pub fn read_tree<F: Read>(binfile: &mut file_bin::BinFile<F>) -> Result<Option<Box<TNode>>, Box<dyn Error>> {
+    let mut seen = [false; 256];
+    Ok(Some(read_node(binfile, 0, &mut seen)?))
+}
+
+/// Reads a node of a tree, refusing what no set of bytes gives: a byte in two leaves, or a
+/// depth no 256 leaves reach, which also keeps corrupt data from recursing without end
+fn read_node<F: Read>(binfile: &mut file_bin::BinFile<F>, depth: usize, seen: &mut [bool; 256]) -> Result<Box<TNode>, Box<dyn Error>> {
+    if depth > 255 {
+        return Err(From::from("[-] Invalid Huffman tree: too deep."));
+    }
     match binfile.read_bit()? {
         true => {
-            if VERBOSE { print!("1"); }
-            Ok(Some(Box::new(TNode::new_branch(read_tree(binfile)?, read_tree(binfile)?))))
+            let left = read_node(binfile, depth + 1, seen)?;
+            let right = read_node(binfile, depth + 1, seen)?;
+            Ok(Box::new(TNode::new_branch(Some(left), Some(right))))
         },
         false => {
-            if VERBOSE { print!("0"); }
             let byte = binfile.read_byte()?;
-            if VERBOSE { print!("{}", byte); }
-            Ok(Some(Box::new(TNode::new(byte))))
-        }
+            if std::mem::replace(&mut seen[byte as usize], true) {
+                return Err(From::from(format!("[-] Invalid Huffman tree: byte {:#04x} in two leaves.", byte)));
+            }
+            Ok(Box::new(TNode::new(byte)))
+        },
     }
 }
 
-pub fn compress_file(in_file: &std::fs::File, out_bin_file: &mut file_bin::BinFile, codes: &[Option<[Option<bool>;30]>; 256]) -> Result<(), Box<dyn Error>> {
+pub fn compress_file<R: Read, F: Write>(mut in_file: R, out_bin_file: &mut file_bin::BinFile<F>, codes: &Codes) -> Result<(), Box<dyn Error>> {
 
-    let mut total = 0;
     loop {
         let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-        let n = in_file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        let n = in_file.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
         if n == 0 { break; }
         for x in chunk {
             if codes[x as usize].is_some() {
                 for i in 0usize..30usize {
                     if codes[x as usize].unwrap()[i].is_some() {
-                        let r = out_bin_file.write_bit(codes[x as usize].unwrap()[i].unwrap())?;
-                        if r {
-                            total += 1;
-                            if VERBOSE { print!("\r[=] [{}] [BYTES]> {}", out_bin_file.path, total); }
-                        }
+                        out_bin_file.write_bit(codes[x as usize].unwrap()[i].unwrap())?;
                     } else {
                         break;
                     }
@@ -246,13 +974,13 @@ pub fn compress_file(in_file: &std::fs::File, out_bin_file: &mut file_bin::BinFi
     Ok(())
 }
 
-pub fn count_diff_chars(mut file: &std::fs::File) -> Result<usize, Box<dyn Error>> {
+pub fn count_diff_chars<R: Read + Seek>(mut file: R) -> Result<usize, Box<dyn Error>> {
     let mut ret: usize = 0;
     let mut found = Vec::new();
 
     loop {
         let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-        let n = file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        let n = file.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
         if n == 0 { break; }
         for x in chunk {
             if !found.contains(&x) {
@@ -270,13 +998,13 @@ pub fn count_diff_chars(mut file: &std::fs::File) -> Result<usize, Box<dyn Error
     Ok(ret)
 }
 
-fn build_list(mut file: &std::fs::File) -> Result<Vec<LNode>, Box<dyn Error>> {
+fn build_list<R: Read + Seek>(mut file: R) -> Result<Vec<LNode>, Box<dyn Error>> {
     // Count array: index is the byte value and value is the count of this byte
     let mut count = [0usize; 256];
     // Read the file chunk by chunk and increment the count array
     loop {
         let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-        let n = file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        let n = file.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
         if n == 0 { break; }
         for x in chunk {
             count[x as usize] += 1;
@@ -287,24 +1015,28 @@ fn build_list(mut file: &std::fs::File) -> Result<Vec<LNode>, Box<dyn Error>> {
     // Reset the file pointer to its begining for later
     file.seek(SeekFrom::Start(0))?;
 
+    Ok(list_from_counts(&count))
+}
+
+/// Builds the Huffman List from the count of every byte
+fn list_from_counts(count: &[usize; 256]) -> Vec<LNode> {
+    let mut count = *count;
     // The return vector containing List Nodes
-    let mut ret = Vec::with_capacity(count_diff_chars(file)?);
+    let mut ret = Vec::with_capacity(count.iter().filter(|&&c| c != 0).count());
     // Loop thru the previously built array
     loop {
         // Get the current minimum value
-        let mut current_min = std::usize::MAX;
+        let mut current_min = usize::MAX;
         let mut current_min_i = 0usize;
-        for i in 0..256 {
-            if count[i] != 0 {
-                if count[i] < current_min {
-                    current_min = count[i];
-                    current_min_i = i;
-                }
+        for (i, &c) in count.iter().enumerate() {
+            if c != 0 && c < current_min {
+                current_min = c;
+                current_min_i = i;
             }
         }
 
         // If we got one, push it at the begining of the list
-        if current_min != std::usize::MAX
+        if current_min != usize::MAX
         {
             // New empty-leafed Tree Node containing the byte
             let new_tnode = TNode::new(current_min_i as u8);
@@ -319,12 +1051,12 @@ fn build_list(mut file: &std::fs::File) -> Result<Vec<LNode>, Box<dyn Error>> {
     }
 
     // Return the vector containing the List Nodes
-    Ok(ret)
+    ret
 }
 
 pub fn build_tree(vec: &mut Vec<LNode>) -> Result<(TNode, usize), Box<dyn Error>> {
     // Check for empty Huffman list
-    if vec.len() == 0 {
+    if vec.is_empty() {
         panic!("[-] Need a non empty list to build a tree.");
     }
 
@@ -359,48 +1091,473 @@ pub fn build_tree(vec: &mut Vec<LNode>) -> Result<(TNode, usize), Box<dyn Error>
 }
 
 /// Generates binary codes for each byte present in the given Huffman Tree by going through it
-pub fn gen_codes(tree: Option<&TNode>, mut current: [Option<bool>;30], current_index: usize, codes: &mut [Option<[Option<bool>; 30]>; 256]) {
-    match tree {
-        Some(tnode) => {
-            match tnode.byte {
-                Some(byte) => {
-                    codes[byte as usize] = Some(current);
-                    return;
-                },
-                None => {
-                    current[current_index] = Some(false);
-                    gen_codes(Some(&*tnode.left.as_ref().unwrap()), current, current_index + 1, codes);
-                    current[current_index] = Some(true);
-                    gen_codes(Some(&*tnode.right.as_ref().unwrap()), current, current_index + 1, codes);
-                },
-            }
+pub fn gen_codes(tree: Option<&TNode>, mut current: [Option<bool>;30], current_index: usize, codes: &mut Codes) {
+    if let Some(tnode) = tree {
+        match tnode.byte {
+            Some(byte) => {
+                codes[byte as usize] = Some(current);
+            },
+            None => {
+                current[current_index] = Some(false);
+                gen_codes(Some(tnode.left.as_ref().unwrap()), current, current_index + 1, codes);
+                current[current_index] = Some(true);
+                gen_codes(Some(tnode.right.as_ref().unwrap()), current, current_index + 1, codes);
+            },
         }
-        None => return,
     }
 }
 
-/// This is the main decompress function
-pub fn decompress(path_in: &String, path_out: &String) -> Result<(), Box<dyn Error>> {
+/// Builds a dictionary from the bytes of the corpus files and saves it, returns its ID
+pub fn train(paths_in: &[String], path_out: &String) -> Result<u32, Box<dyn Error>> {
+    let dictionary = train_dictionary(paths_in)?;
+    dictionary.save(path_out)?;
+    info!("Saved dictionary [{:08x}] to [{}].", dictionary.id(), path_out);
+
+    Ok(dictionary.id())
+}
+
+/// Builds a dictionary from the bytes of the corpus files
+pub fn train_dictionary<P: AsRef<std::path::Path>>(paths_in: &[P]) -> Result<Dictionary, Box<dyn Error>> {
+    let mut freqs = [0u64; 256];
+    for path in paths_in {
+        let count = count_file(std::fs::File::open(path)?)?;
+        for (f, &c) in freqs.iter_mut().zip(count.iter()) {
+            *f += c as u64;
+        }
+        info!("Trained on [{}].", path.as_ref().display());
+    }
+    Ok(Dictionary::train(&freqs))
+}
+
+/// Counts every byte of the file, chunk by chunk
+fn count_file<R: Read>(mut file: R) -> Result<[usize; 256], Box<dyn Error>> {
+    let mut count = [0usize; 256];
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let n = file.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        if n == 0 { break; }
+        for x in chunk {
+            count[x as usize] += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Count and code of a byte
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolStats {
+    pub byte: u8,
+    pub count: usize,
+    /// Code as a string of 0 and 1
+    pub code: String,
+}
+
+/// What a single Huffman tree over the whole file gives
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Size of the file in bytes
+    pub size: usize,
+    /// Number of different bytes
+    pub distinct: usize,
+    /// Shannon entropy, in bits per byte
+    pub entropy: f64,
+    /// Code lengths weighted by the byte counts, in bits per byte
+    pub average_length: f64,
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Entropy over average code length, 1 is the best a prefix code can do
+    pub efficiency: f64,
+    /// Count of the most frequent byte over count of the least frequent one
+    pub count_ratio: f64,
+    /// Bits of the serialized tree
+    pub tree_bits: usize,
+    /// Bits of the coded data
+    pub data_bits: usize,
+    /// Bytes of magic, version, block headers and tree
+    pub header_size: usize,
+    /// Bytes of the compressed file, header included
+    pub compressed_size: usize,
+    /// Compressed size over original size
+    pub ratio: f64,
+    /// Every byte present, most frequent first
+    pub symbols: Vec<SymbolStats>,
+}
+
+/// Computes the statistics of the file without writing anything
+pub fn stats(path_in: &String) -> Result<Stats, Box<dyn Error>> {
+    let count = count_file(std::fs::File::open(path_in)?)?;
+    let size: usize = count.iter().sum();
+    let mut ret = Stats { size, ..Default::default() };
+    // Magic, version, header flags without the original file, end block, size and checksum
+    ret.header_size = MAGIC.len() + 15;
+    ret.compressed_size = ret.header_size;
+    if size == 0 {
+        return Ok(ret);
+    }
+
+    // One code over the whole file, which can be deeper than the trees of its blocks: its lengths
+    // are limited like those of a HuffmanCode
+    let freqs: Vec<u64> = count.iter().map(|&c| c as u64).collect();
+    let codes = CanonicalCode::from_frequencies(&freqs, code::MAX_CODE_LEN).codes(256);
+
+    for (byte, (&c, code)) in count.iter().zip(codes.iter()).enumerate() {
+        if c == 0 {
+            continue;
+        }
+        let code: String = (0..code.len).rev().map(|i| if (code.bits >> i) & 1 != 0 { '1' } else { '0' }).collect();
+        let p = c as f64 / size as f64;
+        ret.entropy -= p * p.log2();
+        ret.data_bits += c * code.len();
+        ret.symbols.push(SymbolStats { byte: byte as u8, count: c, code });
+    }
+    ret.symbols.sort_by(|a, b| b.count.cmp(&a.count).then(a.byte.cmp(&b.byte)));
+    ret.distinct = ret.symbols.len();
+    ret.min_length = ret.symbols.iter().map(|s| s.code.len()).min().unwrap();
+    ret.max_length = ret.symbols.iter().map(|s| s.code.len()).max().unwrap();
+    ret.average_length = ret.data_bits as f64 / size as f64;
+    // A lone byte costs no bits at all
+    ret.efficiency = if ret.data_bits == 0 { 1.0 } else { ret.entropy / ret.average_length };
+    ret.count_ratio = ret.symbols[0].count as f64 / ret.symbols[ret.distinct - 1].count as f64;
+    // Leaves take a flag and a byte, branches a flag
+    ret.tree_bits = 9 * ret.distinct + (ret.distinct - 1);
+    ret.header_size += BLOCK_HEADER_SIZE as usize + ret.tree_bits / 8;
+    // Blocks that would grow are stored
+    let coded = (ret.tree_bits + ret.data_bits).div_ceil(8);
+    ret.compressed_size = MAGIC.len() + 15 + BLOCK_HEADER_SIZE as usize + coded.min(size);
+    ret.ratio = ret.compressed_size as f64 / size as f64;
+
+    Ok(ret)
+}
+
+impl Stats {
+    pub fn to_json(&self) -> String {
+        let symbols: Vec<String> = self.symbols.iter()
+            .map(|s| format!("{{\"byte\":{},\"count\":{},\"code\":{}}}", s.byte, s.count, json_string(&s.code)))
+            .collect();
+        format!(concat!("{{\"size\":{},\"distinct\":{},\"entropy\":{},\"average_length\":{},\"min_length\":{},",
+                        "\"max_length\":{},\"efficiency\":{},\"count_ratio\":{},\"tree_bits\":{},\"data_bits\":{},",
+                        "\"header_size\":{},\"compressed_size\":{},\"ratio\":{},\"symbols\":[{}]}}"),
+                self.size, self.distinct, self.entropy, self.average_length, self.min_length,
+                self.max_length, self.efficiency, self.count_ratio, self.tree_bits, self.data_bits,
+                self.header_size, self.compressed_size, self.ratio, symbols.join(","))
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[+] Size: [{}] bytes, [{}] different", self.size, self.distinct)?;
+        writeln!(f, "[+] Entropy: [{:.4}] bits per byte", self.entropy)?;
+        writeln!(f, "[+] Code length: average [{:.4}], min [{}], max [{}]", self.average_length, self.min_length, self.max_length)?;
+        writeln!(f, "[+] Coding efficiency: [{:.2}%]", self.efficiency * 100.0)?;
+        writeln!(f, "[+] Max/Min byte count ratio: [{:.2}]", self.count_ratio)?;
+        writeln!(f, "[+] Header overhead: [{}] bytes, tree [{}] bits", self.header_size, self.tree_bits)?;
+        writeln!(f, "[+] Compressed size: [{}] bytes, ratio [{:.2}%]", self.compressed_size, self.ratio * 100.0)?;
+        for symbol in &self.symbols {
+            writeln!(f, "[=] {:#04x} {:>10} {}", symbol.byte, symbol.count, symbol.code)?;
+        }
+        Ok(())
+    }
+}
+
+/// This is the main decompress function, blocks coded with a dictionary need it in the options
+pub fn decompress(path_in: &String, path_out: &String, options: &Options) -> Result<Report, Box<dyn Error>> {
+    // Open the file and use a bufreader for it
+    let file_in = std::io::BufReader::new(std::fs::File::open(path_in)?);
+    let file_out = std::io::BufWriter::new(std::fs::File::create(path_out)?);
+    decompress_stream(file_in, file_out, path_in, options).map_err(|e| discard(path_out, e))
+}
+
+/// Decompresses to any writer, `std::io::sink()` only checks the file
+pub fn decompress_to<W: Write>(path_in: &String, writer: W, options: &Options) -> Result<Report, Box<dyn Error>> {
     // Open the file and use a bufreader for it
-    let mut file_in = file_bin::BinFile::open(path_in)?;
-    // Read the first 8bytes: size
-    let nb = file_in.read_size()?;
-    if VERBOSE { println!("[=] [{}][DEC]> {} bytes", path_in, nb); }
-    // Read and build the Huffman Tree
-    if VERBOSE { print!("[=] [{}] [TREE]> ", path_out); }
-    let tree = read_tree(&mut file_in)?;
-    if VERBOSE { println!(); }
-    // Read the compressed data and write the decompressed data in the output file
-    let mut file_out = std::fs::File::create(&path_out)?;
-    let n = decompress_file(&mut file_in, Some(&tree.unwrap()), &mut file_out)?;
-    if VERBOSE { println!(); }
-    println!("[+] Decompressed [{}] bytes.", n);
+    let file_in = std::io::BufReader::new(std::fs::File::open(path_in)?);
+    decompress_stream(file_in, writer, path_in, options)
+}
+
+/// Decompresses a stream, `name` only names the input in messages
+pub fn decompress_stream<R: Read, W: Write>(mut reader: R, writer: W, name: &str, options: &Options) -> Result<Report, Box<dyn Error>> {
+    let mut report = Report::default();
+    // Formats are recognized by their magic, which is then read again
+    let mut magic = Vec::with_capacity(MAGIC.len());
+    reader.by_ref().take(MAGIC.len() as u64).read_to_end(&mut magic)?;
+    let mut file_in = file_bin::BinFile::new(Cursor::new(magic.clone()).chain(reader), name);
+    let mut file_out = Checksummed::new(writer);
+    // bzip2 files are decoded by their own module
+    if magic.starts_with(bzip2::MAGIC) {
+        report.format = Format::Bzip2;
+        let n = bzip2::decompress(&mut file_in, &mut file_out)?;
+        // Every block and the stream carry their CRC
+        report.verified = true;
+        info!("Decompressed [{}] bytes of bzip2 data.", n);
+    // Block format
+    } else if magic == MAGIC {
+        file_in.read_bytes(MAGIC.len())?;
+        let (origin, checksum) = read_header(&mut file_in)?;
+        report.origin = origin;
+        let mut n = 0;
+        let mut ticker = Ticker::new(options);
+        loop {
+            if is_cancelled(options) {
+                return Err(Box::new(Cancelled));
+            }
+            let start = file_in.tell();
+            let mut info = match decompress_block(&mut file_in, &mut file_out, options, &mut report)? {
+                Some(info) => info,
+                None => break,
+            };
+            info.compressed = file_in.tell() - start;
+            n += info.size;
+            report.blocks += 1;
+            report.block_list.push(info);
+            ticker.update(file_in.tell(), file_out.size);
+        }
+        if checksum {
+            let size = file_in.read_bytes(8)?;
+            let crc = read_u32(&mut file_in)? as u32;
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&size);
+            if u64::from_le_bytes(bytes) != file_out.size {
+                return Err(From::from(format!("[-] Length mismatch: [{}] bytes stored, [{}] decoded.", u64::from_le_bytes(bytes), file_out.size)));
+            }
+            if crc != file_out.crc.value() {
+                return Err(From::from(format!("[-] Checksum mismatch: crc32 [{:08x}] stored, [{:08x}] decoded.", crc, file_out.crc.value())));
+            }
+            report.verified = true;
+        }
+        if file_in.read_bytes(1).is_ok() {
+            return Err(From::from("[-] Trailing data after the end block."));
+        }
+        info!("Decompressed [{}] bytes.", n);
+    // Files without magic only hold a size, a tree and the data
+    } else {
+        report.format = Format::Legacy;
+        // Read the first 8bytes: size
+        let nb = file_in.read_size()?;
+        debug!("[{}] [DEC]> {} bytes", name, nb);
+        // Read and build the Huffman Tree
+        let tree = read_tree(&mut file_in)?.unwrap();
+        trace!("[{}] [TREE]> {}", name, tree_string(&tree));
+        report.tree_depth = tree_depth(&tree);
+        // Bytes take no bits with a single leaf: a garbage size would never run out of data
+        if nb as u64 > u32::MAX as u64 {
+            return Err(From::from("[-] Size larger than the format allows."));
+        }
+        // Read the compressed data and write the decompressed data in the output file
+        let n = decompress_file(&mut file_in, Some(&tree), &mut file_out)?;
+        report.block_list.push(BlockInfo { kind: BLOCK_HUFFMAN, size: nb, compressed: file_in.tell(), tables: vec![code_table(&tree)], ..Default::default() });
+        info!("Decompressed [{}] bytes.", n);
+    }
+
+    file_out.flush()?;
+    report.size_in = file_in.tell();
+    report.size_out = file_out.size;
+    report.checksum = file_out.crc.value();
+    Ticker::new(options).finish(report.size_in, report.size_out);
+    Ok(report)
+}
+
+/// Writes the header flags and the fields of the original file they announce
+fn write_header<F: Write>(file_out: &mut file_bin::BinFile<F>, origin: Option<&Origin>) -> Result<(), Box<dyn Error>> {
+    let origin = origin.cloned().unwrap_or_default();
+    let name = origin.name.filter(|name| name.len() <= u16::MAX as usize);
+    let flags = [(name.is_some(), HEADER_NAME), (origin.mode.is_some(), HEADER_MODE), (origin.mtime.is_some(), HEADER_MTIME)];
+    file_out.write_byte(flags.iter().filter(|(set, _)| *set).fold(HEADER_CHECKSUM, |acc, (_, flag)| acc | flag))?;
+    if let Some(name) = name {
+        file_out.write_bytes(&(name.len() as u16).to_le_bytes())?;
+        file_out.write_bytes(name.as_bytes())?;
+    }
+    if let Some(mode) = origin.mode {
+        file_out.write_bytes(&mode.to_le_bytes())?;
+    }
+    if let Some(mtime) = origin.mtime {
+        file_out.write_bytes(&mtime.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads the version and the original file after the magic, None when the header has no field.
+/// Also tells whether the size and checksum follow the end block.
+fn read_header<F: Read>(file_in: &mut file_bin::BinFile<F>) -> Result<(Option<Origin>, bool), Box<dyn Error>> {
+    let flags = match file_in.read_byte()? {
+        VERSION => file_in.read_byte()?,
+        VERSION_NO_HEADER => 0,
+        _ => return Err(From::from("[-] Unsupported format version.")),
+    };
+    if flags & !(HEADER_NAME | HEADER_MODE | HEADER_MTIME | HEADER_CHECKSUM) != 0 {
+        return Err(From::from("[-] Unknown header flags."));
+    }
+    let mut origin = Origin::default();
+    if flags & HEADER_NAME != 0 {
+        let bytes = file_in.read_bytes(2)?;
+        let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        let name = String::from_utf8(file_in.read_bytes(len)?.into_vec()).map_err(|_| "[-] Original name is not valid UTF-8.")?;
+        debug!("[{}] [NAME]> {}", file_in.path, name);
+        origin.name = Some(name);
+    }
+    if flags & HEADER_MODE != 0 {
+        origin.mode = Some(read_u32(file_in)? as u32);
+    }
+    if flags & HEADER_MTIME != 0 {
+        let mut mtime = [0u8; 8];
+        mtime.copy_from_slice(&file_in.read_bytes(8)?);
+        origin.mtime = Some(i64::from_le_bytes(mtime));
+    }
+    Ok((Some(origin).filter(|origin| *origin != Origin::default()), flags & HEADER_CHECKSUM != 0))
+}
+
+/// Original file recorded in a compressed file, read without decompressing it
+pub fn origin(path_in: &String) -> Result<Option<Origin>, Box<dyn Error>> {
+    let mut file_in = file_bin::BinFile::new(std::io::BufReader::new(std::fs::File::open(path_in)?), path_in);
+    match file_in.read_bytes(MAGIC.len()) {
+        Ok(magic) if *magic == MAGIC[..] => Ok(read_header(&mut file_in)?.0),
+        _ => Ok(None),
+    }
+}
+
+/// Writer keeping the size and checksum of what goes through it
+struct Checksummed<W: Write> {
+    inner: W,
+    size: u64,
+    crc: Crc32,
+}
+
+impl<W: Write> Checksummed<W> {
+    fn new(inner: W) -> Self {
+        Checksummed { inner, size: 0, crc: Crc32::new() }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads a little-endian u32 from the given binary file
+fn read_u32<F: Read>(binfile: &mut file_bin::BinFile<F>) -> Result<usize, Box<dyn Error>> {
+    let bytes = binfile.read_bytes(4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Decompresses the next block, returns what it held or None at the end of the file
+fn decompress_block<F: Read, W: Write>(binfile: &mut file_bin::BinFile<F>, out_file: &mut W, options: &Options, report: &mut Report) -> Result<Option<BlockInfo>, Box<dyn Error>> {
+    // Block header: kind, flags, size, and for transformed blocks the BWT origin and coded size
+    let kind = match binfile.read_byte()? {
+        BLOCK_END => return Ok(None),
+        kind @ (BLOCK_HUFFMAN | BLOCK_MULTI | BLOCK_ANS | BLOCK_RANGE | BLOCK_WORDS | BLOCK_UTF8 | BLOCK_DICTIONARY | BLOCK_STORED) => kind,
+        _ => return Err(From::from("[-] Unknown block kind.")),
+    };
+    let flags = binfile.read_byte()?;
+    let size = read_u32(binfile)?;
+    let origin = match flags & FLAG_TRANSFORM != 0 {
+        true => Some(read_u32(binfile)?),
+        false => None,
+    };
+    let coded = match origin {
+        Some(_) => read_u32(binfile)?,
+        None => size,
+    };
+    // Nothing gets allocated for sizes compress never writes
+    if size > BLOCK_SIZE || coded > MAX_CODED_SIZE {
+        return Err(From::from("[-] Block larger than the format allows."));
+    }
+    let mut info = BlockInfo { kind, size, transform: origin.is_some(), ..Default::default() };
+
+    let mut data = Vec::with_capacity(coded);
+    if kind == BLOCK_STORED {
+        data = binfile.read_bytes(coded)?.into_vec();
+    } else if kind == BLOCK_ANS {
+        let len = read_u32(binfile)?;
+        if len > ans_max_size(coded) {
+            return Err(From::from("[-] ANS payload larger than the block."));
+        }
+        data = ans_decode(&binfile.read_bytes(len)?, coded)?;
+    } else if kind == BLOCK_RANGE {
+        let len = read_u32(binfile)?;
+        if len > range::max_compressed_size(coded) {
+            return Err(From::from("[-] Range coded payload larger than the block."));
+        }
+        data = range::decompress(&binfile.read_bytes(len)?, coded)?;
+    } else if kind == BLOCK_DICTIONARY {
+        let id = read_u32(binfile)? as u32;
+        let dictionary = match &options.dictionary {
+            Some(dictionary) if dictionary.id() == id => dictionary,
+            _ => return Err(From::from(format!("[-] Block needs dictionary [{:08x}].", id))),
+        };
+        data = dictionary.code().decode(binfile).take(coded).collect::<Result<_, _>>()?;
+    } else if kind == BLOCK_WORDS {
+        data = words::decode(binfile, coded)?;
+    } else if kind == BLOCK_UTF8 {
+        data = utf8::decode(binfile, coded)?;
+    } else if kind == BLOCK_MULTI {
+        decompress_segments(binfile, coded, &mut data, report, &mut info)?;
+    } else {
+        // Read and build the Huffman Tree
+        let tree = read_tree(binfile)?.unwrap();
+        trace!("[{}] [TREE]> {}", binfile.path, tree_string(&tree));
+        report.tree_depth = report.tree_depth.max(tree_depth(&tree));
+        info.tables.push(code_table(&tree));
+        binfile.size = Some(coded);
+        decompress_file(binfile, Some(&tree), &mut data)?;
+    }
+    if let Some(origin) = origin {
+        data = transform::decode(&data, origin, size)?;
+    }
+    if data.len() != size {
+        return Err(From::from("[-] Block size mismatch."));
+    }
+    out_file.write_all(&data)?;
+    // Blocks end on a byte boundary
+    binfile.align();
+
+    Ok(Some(info))
+}
+
+/// Decompresses the `coded` bytes of a block with several tables, after its header
+fn decompress_segments<F: Read, W: Write>(binfile: &mut file_bin::BinFile<F>, coded: usize, out_file: &mut W, report: &mut Report, info: &mut BlockInfo) -> Result<(), Box<dyn Error>> {
+    let nb = binfile.read_byte()? as usize;
+    if nb == 0 {
+        return Err(From::from("[-] Block without tables."));
+    }
+    let bits = selector_bits(nb);
+    let count = coded.div_ceil(SEGMENT_SIZE);
+    if count > MAX_CODED_SIZE.div_ceil(SEGMENT_SIZE) {
+        return Err(From::from("[-] Too many segments."));
+    }
+    let mut selectors = Vec::with_capacity(count);
+    for _ in 0..count {
+        let selector = binfile.read_bits(bits)? as usize;
+        if selector >= nb {
+            return Err(From::from("[-] Invalid table selector."));
+        }
+        selectors.push(selector);
+    }
+    let mut trees = Vec::with_capacity(nb);
+    for _ in 0..nb {
+        let tree = read_tree(binfile)?.unwrap();
+        trace!("[{}] [TREE]> {}", binfile.path, tree_string(&tree));
+        report.tree_depth = report.tree_depth.max(tree_depth(&tree));
+        info.tables.push(code_table(&tree));
+        trees.push(tree);
+    }
+
+    for (i, &selector) in selectors.iter().enumerate() {
+        binfile.size = Some(SEGMENT_SIZE.min(coded - i * SEGMENT_SIZE));
+        decompress_file(binfile, Some(&trees[selector]), out_file)?;
+    }
 
     Ok(())
 }
 
 /// This the function that actually performs the Huffman decompression
-pub fn decompress_file(binfile: &mut file_bin::BinFile, tree: Option<&TNode>, out_file: &mut std::fs::File) -> Result<usize, Box<dyn Error>> {
+pub fn decompress_file<F: Read, W: Write>(binfile: &mut file_bin::BinFile<F>, tree: Option<&TNode>, out_file: &mut W) -> Result<usize, Box<dyn Error>> {
     // Returned number of bytes decompressed
     let mut ret = 0;
     // Check if we already read the size from the binary file
@@ -414,7 +1571,6 @@ pub fn decompress_file(binfile: &mut file_bin::BinFile, tree: Option<&TNode>, ou
         let byte = decompress_byte(binfile, tree)?;
         // Write it to the output file
         out_file.write_all(&[byte])?;
-        if VERBOSE { print!("\r[=] [{}] [BYTES]> {}", binfile.path, ret); }
         // Increment the byte count
         ret += 1;
     }
@@ -423,24 +1579,57 @@ pub fn decompress_file(binfile: &mut file_bin::BinFile, tree: Option<&TNode>, ou
 }
 
 /// Recursive function that reads a single byte from the given binary file
-pub fn decompress_byte(binfile: &mut file_bin::BinFile, node: Option<&TNode>) -> Result<u8, Box<dyn Error>> {
+pub fn decompress_byte<F: Read>(binfile: &mut file_bin::BinFile<F>, node: Option<&TNode>) -> Result<u8, Box<dyn Error>> {
     if node.unwrap().byte.is_some() {
         return Ok(node.unwrap().byte.unwrap());
     }
 
     let bit = binfile.read_bit()?;
     if !bit {
-        return decompress_byte(binfile, Some(&*node.unwrap().left.as_ref().unwrap()));
+        return decompress_byte(binfile, Some(node.unwrap().left.as_ref().unwrap()));
     }
 
-    return decompress_byte(binfile, Some(&*node.unwrap().right.as_ref().unwrap()));
+    decompress_byte(binfile, Some(node.unwrap().right.as_ref().unwrap()))
 }
 
 /// TESTS
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    /// Path in the temporary directory, unique to the process and the test, removed when dropped
+    pub(crate) struct TempPath(String);
+
+    impl TempPath {
+        pub(crate) fn new(name: &str) -> Self {
+            let thread = std::thread::current();
+            let test = thread.name().unwrap_or("test").replace("::", "-");
+            let path = std::env::temp_dir().join(format!("huffman_{}_{}_{}", std::process::id(), test, name));
+            TempPath(String::from(path.to_str().unwrap()))
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = String;
+
+        fn deref(&self) -> &String {
+            &self.0
+        }
+    }
+
+    impl AsRef<std::path::Path> for TempPath {
+        fn as_ref(&self) -> &std::path::Path {
+            std::path::Path::new(&self.0)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let path = std::path::Path::new(&self.0);
+            let _ = if path.is_dir() { std::fs::remove_dir_all(path) } else { std::fs::remove_file(path) };
+        }
+    }
+
     #[test]
     fn check_nb_diff_chars() -> Result<(), Box<dyn Error>> {
         let file_lazydog = std::fs::File::open("./data/lazy_dog.txt")?;
@@ -481,6 +1670,412 @@ mod tests {
         assert_eq!(count_lazydog as u64, file_lazydog.metadata().unwrap().len());
         assert_eq!(count_tara as u64, file_tara.metadata().unwrap().len());
 
+        let leaves = TNode::new_branch(Some(Box::new(TNode::new(b't'))), Some(Box::new(TNode::new(b'\n'))));
+        let tree = TNode::new_branch(Some(Box::new(TNode::new(b'a'))), Some(Box::new(leaves)));
+        assert_eq!(tree_string(&tree), "109710116010");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_decompress_bzip2() -> Result<(), Box<dyn Error>> {
+        let path_out = TempPath::new("lazy_dog.dhuff");
+        decompress(&String::from("./data/lazy_dog.txt.bz2"), &path_out, &Options::default())?;
+
+        assert_eq!(std::fs::read(&path_out)?, std::fs::read("./data/lazy_dog.txt")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_round_trip() -> Result<(), Box<dyn Error>> {
+        for (name, transform) in [("lazy_dog.txt", false), ("tara.txt", true), ("empty.txt", false), ("lines.txt.bz2", true)] {
+            let path_in = format!("./data/{}", name);
+            let path_huff = TempPath::new(&format!("{}_{}.huff", name, transform));
+            let path_out = TempPath::new(&format!("{}_{}.dhuff", name, transform));
+
+            compress(&path_in, &path_huff, &Options { transform, tables: 2, ..Default::default() })?;
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, std::fs::read(&path_in)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_multiple_tables() -> Result<(), Box<dyn Error>> {
+        // Two halves using different bytes: one table each beats a shared one
+        let mut data: Vec<u8> = (0..32768u32).map(|i| b"abcd"[(i.wrapping_mul(2654435761) >> 30) as usize]).collect();
+        data.extend((0..32768u32).map(|i| b"wxyz"[(i.wrapping_mul(2246822519) >> 30) as usize]));
+        let (selectors, counts) = cluster_segments(&data, 4);
+        assert_eq!(counts.len(), 2);
+        assert!(selectors[..32].iter().all(|&s| s == selectors[0]) && selectors[32..].iter().all(|&s| s != selectors[0]));
+
+        let path_in = TempPath::new("tables.txt");
+        std::fs::write(&path_in, &data)?;
+        let path_huff = TempPath::new("tables.huff");
+        let path_out = TempPath::new("tables.dhuff");
+        let mut sizes = Vec::new();
+        for tables in [1, 4] {
+            compress(&path_in, &path_huff, &Options { tables, ..Default::default() })?;
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, data);
+            sizes.push(std::fs::metadata(&path_huff)?.len());
+        }
+        assert!(sizes[1] < sizes[0] * 3 / 4);
+
+        // The listing of the last file shows its two tables, one per half
+        let report = decompress_to(&path_huff, std::io::sink(), &Options::default())?;
+        assert_eq!((report.format, report.size_out), (Format::Blocks, data.len() as u64));
+        assert_eq!(report.block_list.len(), 1);
+        assert_eq!((report.block_list[0].name(), report.block_list[0].tables.len()), ("multi", 2));
+        assert_eq!(report.block_list[0].compressed, sizes[1] - MAGIC.len() as u64 - 15);
+        assert_eq!(report.block_list[0].tables[0].len(), 4);
+
+        // A crafted coded size is refused before anything is allocated for it
+        let mut crafted = MAGIC.to_vec();
+        crafted.extend_from_slice(&[VERSION, 0, BLOCK_MULTI, FLAG_TRANSFORM, 10, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1]);
+        assert!(decompress_stream(&crafted[..], std::io::sink(), "crafted", &Options::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_entropy_coders() -> Result<(), Box<dyn Error>> {
+        // Skewed data: Huffman spends at least 1 bit per byte, ANS and the range coder get close to the 0.3 bit entropy
+        let data: Vec<u8> = (0..65536u32).map(|i| if (i.wrapping_mul(2654435761) >> 24) < 13 { b'b' } else { b'a' }).collect();
+        let path_in = TempPath::new("skewed.txt");
+        std::fs::write(&path_in, &data)?;
+
+        let mut sizes = Vec::new();
+        for entropy in [Entropy::Huffman, Entropy::Ans, Entropy::Range] {
+            let path_huff = TempPath::new(&format!("skewed_{:?}.huff", entropy));
+            let path_out = TempPath::new(&format!("skewed_{:?}.dhuff", entropy));
+            compress(&path_in, &path_huff, &Options { entropy, ..Default::default() })?;
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, data);
+            sizes.push(std::fs::metadata(&path_huff)?.len());
+        }
+        assert!(sizes[1] < sizes[0] / 2);
+        assert!(sizes[2] < sizes[0] / 2);
+
+        // A single distinct byte falls back to Huffman
+        assert!(ans_encode(b"aaaa")?.is_none());
+
+        // Random bytes cost the most, still under the bound the decoder checks
+        let random: Vec<u8> = (0..65536u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        assert!(ans_encode(&random)?.unwrap().len() <= ans_max_size(random.len()));
+
+        // A crafted payload length is refused before anything is allocated for it
+        for kind in [BLOCK_ANS, BLOCK_RANGE] {
+            let mut crafted = MAGIC.to_vec();
+            crafted.extend_from_slice(&[VERSION, 0, kind, 0, 10, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 1]);
+            assert!(decompress_stream(&crafted[..], std::io::sink(), "crafted", &Options::default()).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_words() -> Result<(), Box<dyn Error>> {
+        // English text: a few bits per word beat several bits per byte
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            data.extend_from_slice(if i % 3 == 0 { &b"tara tata\n"[..] } else { &b"The quick brown fox jumps over the lazy dog\n"[..] });
+        }
+        let path_in = TempPath::new("words.txt");
+        std::fs::write(&path_in, &data)?;
+
+        let mut sizes = Vec::new();
+        for alphabet in [Alphabet::Bytes, Alphabet::Words] {
+            let path_huff = TempPath::new(&format!("words_{:?}.huff", alphabet));
+            let path_out = TempPath::new(&format!("words_{:?}.dhuff", alphabet));
+            compress(&path_in, &path_huff, &Options { alphabet, ..Default::default() })?;
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, data);
+            sizes.push(std::fs::metadata(&path_huff)?.len());
+        }
+        assert!(sizes[1] < sizes[0] / 4);
+
+        // The words of `seq 0 80000`: too many to list, the block is coded byte by byte
+        let data: Vec<u8> = (0..=80000).flat_map(|i| format!("{}\n", i).into_bytes()).collect();
+        let mut huff = Vec::new();
+        compress_stream(&data[..], &mut huff, "seq", &Options { alphabet: Alphabet::Words, ..Default::default() })?;
+        let mut out = Vec::new();
+        let report = decompress_stream(&huff[..], &mut out, "seq", &Options::default())?;
+        assert_eq!(out, data);
+        assert_eq!(report.block_list[0].name(), "huffman");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_utf8() -> Result<(), Box<dyn Error>> {
+        // Greek and Japanese: one symbol per character instead of 2 or 3 bytes, split over the block boundary
+        let mut data = Vec::new();
+        let mut i = 0u32;
+        while data.len() < BLOCK_SIZE + 30000 {
+            i = i.wrapping_mul(1103515245).wrapping_add(12345);
+            let words = ["αλφα ", "βήτα ", "γάμμα ", "ひらがな", "カタカナ", "漢字", "。\n"];
+            data.extend_from_slice(words[(i >> 16) as usize % words.len()].as_bytes());
+        }
+        data.push(0xff);
+        let path_in = TempPath::new("utf8.txt");
+        std::fs::write(&path_in, &data)?;
+
+        let mut sizes = Vec::new();
+        for alphabet in [Alphabet::Bytes, Alphabet::Utf8] {
+            let path_huff = TempPath::new(&format!("utf8_{:?}.huff", alphabet));
+            let path_out = TempPath::new(&format!("utf8_{:?}.dhuff", alphabet));
+            compress(&path_in, &path_huff, &Options { alphabet, ..Default::default() })?;
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, data);
+            sizes.push(std::fs::metadata(&path_huff)?.len());
+        }
+        assert!(sizes[1] < sizes[0] * 3 / 4);
+
+        // Every code point once: the full blocks have too many to list and are coded byte by byte
+        let data: String = (0..=char::MAX as u32).filter_map(char::from_u32).collect();
+        let mut huff = Vec::new();
+        compress_stream(data.as_bytes(), &mut huff, "all", &Options { alphabet: Alphabet::Utf8, ..Default::default() })?;
+        let mut out = Vec::new();
+        let report = decompress_stream(&huff[..], &mut out, "all", &Options::default())?;
+        assert_eq!(out, data.as_bytes());
+        assert!(report.block_list.len() > 1 && report.block_list[0].kind != BLOCK_UTF8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_dictionary() -> Result<(), Box<dyn Error>> {
+        let path_dict = TempPath::new("test.hdict");
+        let id = train(&[String::from("./data/lazy_dog.txt"), String::from("./data/tara.txt")], &path_dict)?;
+        let dictionary = Dictionary::load(&path_dict)?;
+        assert_eq!(dictionary.id(), id);
+
+        // A small message: no tree to store
+        let path_in = TempPath::new("message.txt");
+        std::fs::write(&path_in, b"the lazy fox jumps over tata\n")?;
+        let path_tree = TempPath::new("message_tree.huff");
+        let path_dict_huff = TempPath::new("message_dict.huff");
+        let path_out = TempPath::new("message.dhuff");
+        let options = Options { dictionary: Some(dictionary), ..Default::default() };
+        compress(&path_in, &path_tree, &Options::default())?;
+        compress(&path_in, &path_dict_huff, &options)?;
+        assert!(std::fs::metadata(&path_dict_huff)?.len() < std::fs::metadata(&path_tree)?.len());
+
+        assert!(decompress(&path_dict_huff, &path_out, &Options::default()).is_err());
+        decompress(&path_dict_huff, &path_out, &options)?;
+        assert_eq!(std::fs::read(&path_out)?, std::fs::read(&path_in)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_stored() -> Result<(), Box<dyn Error>> {
+        // Random bytes do not compress: header, one block header and the data
+        let data: Vec<u8> = (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8 ^ (i >> 3) as u8).collect();
+        let path_in = TempPath::new("random.bin");
+        let path_huff = TempPath::new("random.huff");
+        let path_out = TempPath::new("random.dhuff");
+        std::fs::write(&path_in, &data)?;
+
+        for entropy in [Entropy::Huffman, Entropy::Range] {
+            compress(&path_in, &path_huff, &Options { entropy, ..Default::default() })?;
+            assert_eq!(std::fs::metadata(&path_huff)?.len(), (MAGIC.len() + 2) as u64 + BLOCK_HEADER_SIZE + data.len() as u64 + 13);
+            decompress(&path_huff, &path_out, &Options::default())?;
+            assert_eq!(std::fs::read(&path_out)?, data);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_stats() -> Result<(), Box<dyn Error>> {
+        // 4 a, 3 t, 1 r, 1 space, 1 newline
+        let tara = stats(&String::from("./data/tara.txt"))?;
+        assert_eq!((tara.size, tara.distinct, tara.min_length, tara.max_length), (10, 5, 1, 4));
+        assert_eq!(tara.data_bits, 21);
+        assert!((tara.entropy - 2.0464).abs() < 1e-4);
+        assert!((tara.average_length - 2.1).abs() < 1e-9);
+        assert!((tara.count_ratio - 4.0).abs() < 1e-9);
+        assert_eq!((tara.symbols[0].byte, tara.symbols[0].code.len()), (b'a', 1));
+        assert_eq!(tara.tree_bits, 49);
+
+        // The estimate matches the file compress writes
+        for name in ["./data/lazy_dog.txt", "./data/tara.txt"] {
+            let path_huff = TempPath::new("stats.huff");
+            compress(&String::from(name), &path_huff, &Options::default())?;
+            assert_eq!(stats(&String::from(name))?.compressed_size as u64, std::fs::metadata(&path_huff)?.len());
+        }
+
+        assert_eq!(stats(&String::from("./data/empty.txt"))?.compressed_size, 19);
+
+        // Fibonacci counts over several blocks would need a tree deeper than 30 levels
+        let path_in = TempPath::new("fibonacci.bin");
+        let (mut data, mut previous, mut count) = (Vec::new(), 1usize, 1usize);
+        for byte in 0..32u8 {
+            data.extend(std::iter::repeat_n(byte, count));
+            (previous, count) = (count, previous + count);
+        }
+        std::fs::write(&path_in, &data)?;
+        let fibonacci = stats(&path_in)?;
+        assert_eq!((fibonacci.distinct, fibonacci.max_length), (32, 31));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_report() -> Result<(), Box<dyn Error>> {
+        let path_huff = TempPath::new("report.huff");
+        let path_out = TempPath::new("report.dhuff");
+        let path_in = TempPath::new("report.txt");
+        std::fs::write(&path_in, std::fs::read("./data/lazy_dog.txt")?.repeat(10))?;
+        let compressed = compress(&path_in, &path_huff, &Options::default())?;
+        let decompressed = decompress(&path_huff, &path_out, &Options::default())?;
+        assert_eq!((compressed.size_in, compressed.size_out), (440, std::fs::metadata(&path_huff)?.len()));
+        assert_eq!((decompressed.size_in, decompressed.size_out), (compressed.size_out, compressed.size_in));
+        assert_eq!(compressed.checksum, 0x8cf5_318f);
+        assert_eq!(decompressed.checksum, compressed.checksum);
+        assert_eq!((compressed.blocks, compressed.tree_depth), (1, decompressed.tree_depth));
+        assert!(compressed.tree_depth > 0);
+
+        let json = compressed.to_json(true, "a\"b", "c", 0.5);
+        assert!(json.starts_with("{\"mode\":\"compress\",\"input\":\"a\\\"b\",\"output\":\"c\",\"size_in\":440,"));
+        assert!(json.contains("\"checksum\":\"8cf5318f\""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_streams() -> Result<(), Box<dyn Error>> {
+        let data = std::fs::read("./data/lazy_dog.txt")?.repeat(100);
+        let mut compressed = Vec::new();
+        let report = compress_stream(&data[..], &mut compressed, "memory", &Options::default())?;
+        assert_eq!((report.size_in, report.size_out), (data.len() as u64, compressed.len() as u64));
+
+        let mut decompressed = Vec::new();
+        let report = decompress_stream(&compressed[..], &mut decompressed, "memory", &Options::default())?;
+        assert_eq!(decompressed, data);
+        assert_eq!(report.size_in, compressed.len() as u64);
+
+        // The magic is peeked from the stream, bzip2 included
+        decompressed.clear();
+        decompress_stream(std::fs::File::open("./data/lazy_dog.txt.bz2")?, &mut decompressed, "bzip2", &Options::default())?;
+        assert_eq!(decompressed, std::fs::read("./data/lazy_dog.txt")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_progress() -> Result<(), Box<dyn Error>> {
+        let path_in = TempPath::new("progress.txt");
+        let path_huff = TempPath::new("progress.huff");
+        let path_out = TempPath::new("progress.dhuff");
+        std::fs::write(&path_in, std::fs::read("./data/lazy_dog.txt")?.repeat(60_000))?;
+
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = calls.clone();
+        let options = Options { progress: Some(Progress::new(1, move |i, o| sink.lock().unwrap().push((i, o)))), ..Default::default() };
+        let report = compress(&path_in, &path_huff, &options)?;
+        // Three blocks, then the end of the file
+        assert_eq!(calls.lock().unwrap().len(), 4);
+        assert_eq!(calls.lock().unwrap()[0].0, BLOCK_SIZE as u64);
+        assert_eq!(calls.lock().unwrap().last(), Some(&(report.size_in, report.size_out)));
+
+        calls.lock().unwrap().clear();
+        let report = decompress(&path_huff, &path_out, &options)?;
+        assert_eq!(calls.lock().unwrap().len(), 4);
+        assert_eq!(calls.lock().unwrap().last(), Some(&(report.size_in, report.size_out)));
+
+        // A large interval only leaves the last call
+        calls.lock().unwrap().clear();
+        let sink = calls.clone();
+        let options = Options { progress: Some(Progress::new(u64::MAX, move |i, o| sink.lock().unwrap().push((i, o)))), ..Default::default() };
+        compress(&path_in, &path_huff, &options)?;
+        assert_eq!(calls.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_cancel() -> Result<(), Box<dyn Error>> {
+        let path_in = TempPath::new("cancel.txt");
+        let path_huff = TempPath::new("cancel.huff");
+        let path_out = TempPath::new("cancel.dhuff");
+        std::fs::write(&path_in, std::fs::read("./data/tara.txt")?.repeat(250_000))?;
+        compress(&path_in, &path_huff, &Options::default())?;
+
+        // Cancelled from the progress hook once the first block is done
+        let cancel = CancelToken::new();
+        let token = cancel.clone();
+        let options = Options {
+            progress: Some(Progress::new(0, move |_, _| token.cancel())),
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let e = compress(&path_in, &path_huff, &options).unwrap_err();
+        assert!(e.is::<Cancelled>());
+        assert!(!path_huff.as_ref().exists());
+
+        compress(&path_in, &path_huff, &Options::default())?;
+        let e = decompress(&path_huff, &path_out, &options).unwrap_err();
+        assert!(e.is::<Cancelled>());
+        assert!(!path_out.as_ref().exists());
+
+        // Failed runs do not leave their output either
+        assert!(decompress(&String::from("./data/tara.txt"), &path_out, &Options::default()).is_err());
+        assert!(!path_out.as_ref().exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_origin() -> Result<(), Box<dyn Error>> {
+        let path_huff = TempPath::new("origin.huff");
+        let path_out = TempPath::new("origin.txt");
+        let origin = Origin { name: Some(String::from("lazy_dog.txt")), mode: Some(0o640), mtime: Some(1577934245) };
+        compress(&String::from("./data/lazy_dog.txt"), &path_huff, &Options { origin: Some(origin.clone()), ..Default::default() })?;
+        assert_eq!(super::origin(&path_huff)?, Some(origin.clone()));
+
+        let report = decompress(&path_huff, &path_out, &Options::default())?;
+        assert_eq!(report.origin, Some(origin.clone()));
+        origin.restore(path_out.as_ref())?;
+        assert_eq!(Origin::of(path_out.as_ref())?, Origin { name: path_out.as_ref().file_name().and_then(|n| n.to_str()).map(String::from), ..origin });
+
+        // Version 2 files have no header flags
+        let mut out = Vec::new();
+        let report = decompress_stream(&[b'H', b'U', b'F', b'F', VERSION_NO_HEADER, BLOCK_END][..], &mut out, "v2", &Options::default())?;
+        assert_eq!((report.origin, out.len()), (None, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_integrity() -> Result<(), Box<dyn Error>> {
+        let mut data = Vec::new();
+        compress_stream(&b"the quick brown fox jumps over the lazy dog"[..], &mut data, "integrity", &Options::default())?;
+        let report = decompress_stream(&data[..], std::io::sink(), "integrity", &Options::default())?;
+        assert!(report.verified);
+
+        // Stored checksum, stored length, trailing byte
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(decompress_stream(&corrupt[..], std::io::sink(), "integrity", &Options::default()).is_err());
+        let mut corrupt = data.clone();
+        let len = corrupt.len();
+        corrupt[len - 12] ^= 1;
+        assert!(decompress_stream(&corrupt[..], std::io::sink(), "integrity", &Options::default()).is_err());
+        let mut corrupt = data.clone();
+        corrupt.push(0);
+        assert!(decompress_stream(&corrupt[..], std::io::sink(), "integrity", &Options::default()).is_err());
+
+        // Trees that recurse forever, or branch to two leaves of 0x41
+        let mut deep = file_bin::BinFile::new(&[0xffu8; 64][..], "deep");
+        assert!(read_tree(&mut deep).is_err());
+        let mut twice = file_bin::BinFile::new(&[0x90u8, 0x48, 0x20][..], "twice");
+        assert!(read_tree(&mut twice).is_err());
+
         Ok(())
     }
 
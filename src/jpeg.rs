@@ -0,0 +1,509 @@
+use crate::canonical::{CanonicalCode, Code};
+use crate::file_bin::{BitRead, BitWrite};
+
+use std::error::Error;
+
+/// Longest code allowed in a JPEG Huffman table
+pub const MAX_CODE_LEN: u8 = 16;
+/// Number of coefficients in a 8x8 block
+pub const BLOCK_SIZE: usize = 64;
+/// Largest magnitude categories of baseline DC differences and AC coefficients (Tables F.1 and F.2)
+const MAX_DC_CATEGORY: u8 = 11;
+const MAX_AC_CATEGORY: u8 = 10;
+
+/// Table class stored in the high nibble of Tc/Th in a DHT segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableClass {
+    Dc = 0,
+    Ac = 1,
+}
+
+/// Table class, destination identifier and table of one entry in a DHT segment
+pub type DhtEntry = (TableClass, u8, HuffmanTable);
+
+/// A baseline JPEG Huffman table as stored in a DHT marker segment (ITU T.81 B.2.4.2):
+/// the number of codes of each length from 1 to 16 (BITS) and the symbols in code order (HUFFVAL).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HuffmanTable {
+    pub bits: [u8; 16],
+    pub huffval: Vec<u8>,
+}
+
+impl HuffmanTable {
+    /// Creates a table from BITS and HUFFVAL, checking that they describe a valid JPEG code
+    pub fn new(bits: [u8; 16], huffval: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        let table = HuffmanTable { bits, huffval };
+        // The all 1-bits code of the longest length is reserved (Annex C)
+        table.code()?;
+        let used: u32 = bits.iter().enumerate().map(|(i, &n)| (n as u32) << (15 - i)).sum();
+        if used >= 1 << 16 {
+            return Err(From::from("[-] JPEG Huffman table uses the reserved all 1-bits code."));
+        }
+
+        Ok(table)
+    }
+
+    /// Derives the optimal table for the given symbol frequencies (Annex K.2)
+    pub fn optimized(freqs: &[u64; 256]) -> Self {
+        // An extra symbol with the lowest weight takes the all 1-bits code, dropping it reserves the code
+        let mut weights = freqs.to_vec();
+        weights.push(1);
+        let code = CanonicalCode::from_frequencies(&weights, MAX_CODE_LEN);
+
+        let mut bits = [0u8; 16];
+        for (len, &count) in code.counts().iter().enumerate().skip(1) {
+            bits[len - 1] = count as u8;
+        }
+        let huffval: Vec<u8> = code.symbols().iter().filter(|&&s| s != 256).map(|&s| s as u8).collect();
+        // The reserved symbol sorts last among the longest codes
+        bits[code.max_len() as usize - 1] -= 1;
+
+        HuffmanTable { bits, huffval }
+    }
+
+    /// Canonical code described by this table
+    pub fn code(&self) -> Result<CanonicalCode, Box<dyn Error>> {
+        let mut counts = vec![0u32];
+        counts.extend(self.bits.iter().map(|&n| n as u32));
+        while counts.len() > 1 && *counts.last().unwrap() == 0 {
+            counts.pop();
+        }
+        CanonicalCode::new(counts, self.huffval.iter().map(|&v| v as u32).collect())
+    }
+
+    /// Code of every symbol (EHUFCO/EHUFSI of Annex C), length 0 for absent symbols
+    pub fn codes(&self) -> Result<Vec<Code>, Box<dyn Error>> {
+        Ok(self.code()?.codes(256))
+    }
+
+    /// Parses every table of a DHT segment payload (after the length field)
+    pub fn parse_dht(mut payload: &[u8]) -> Result<Vec<DhtEntry>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 17 {
+                return Err(From::from("[-] Truncated DHT segment."));
+            }
+            let class = match payload[0] >> 4 {
+                0 => TableClass::Dc,
+                1 => TableClass::Ac,
+                _ => return Err(From::from("[-] Invalid table class in DHT segment.")),
+            };
+            let id = payload[0] & 0x0f;
+            let mut bits = [0u8; 16];
+            bits.copy_from_slice(&payload[1..17]);
+            let nb: usize = bits.iter().map(|&n| n as usize).sum();
+            if payload.len() < 17 + nb {
+                return Err(From::from("[-] Truncated DHT segment."));
+            }
+            let table = HuffmanTable::new(bits, payload[17..17 + nb].to_vec())?;
+            ret.push((class, id, table));
+            payload = &payload[17 + nb..];
+        }
+        Ok(ret)
+    }
+
+    /// Serializes the table as a complete DHT marker segment
+    pub fn to_dht(&self, class: TableClass, id: u8) -> Vec<u8> {
+        let length = 2 + 1 + 16 + self.huffval.len();
+        let mut ret = vec![0xff, 0xc4, (length >> 8) as u8, length as u8, ((class as u8) << 4) | (id & 0x0f)];
+        ret.extend_from_slice(&self.bits);
+        ret.extend_from_slice(&self.huffval);
+        ret
+    }
+}
+
+/// Reads the bits of an entropy-coded segment, dropping the 0x00 stuffed after each 0xFF (F.2.2.5)
+pub struct SegmentReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u8,
+    offset: u8,
+    marker: Option<u8>,
+}
+
+impl<'a> SegmentReader<'a> {
+    /// Starts reading at the beginning of `data`
+    pub fn new(data: &'a [u8]) -> Self {
+        SegmentReader { data, pos: 0, buffer: 0, offset: 0, marker: None }
+    }
+
+    /// Marker that ended the segment, if one was reached
+    pub fn marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    /// Position of the next unread byte in `data`
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Skips the rest of the current byte and an RSTm marker, returns m
+    pub fn restart(&mut self) -> Result<u8, Box<dyn Error>> {
+        self.offset = 0;
+        if self.marker.is_none() {
+            if self.pos + 1 >= self.data.len() || self.data[self.pos] != 0xff {
+                return Err(From::from("[-] Expected a restart marker."));
+            }
+            self.marker = Some(self.data[self.pos + 1]);
+            self.pos += 2;
+        }
+        match self.marker.take() {
+            Some(m @ 0xd0..=0xd7) => Ok(m - 0xd0),
+            _ => Err(From::from("[-] Expected a restart marker.")),
+        }
+    }
+}
+
+impl BitRead for SegmentReader<'_> {
+    fn read_bit(&mut self) -> Result<bool, Box<dyn Error>> {
+        // If we need a new byte, take it from the segment
+        if self.offset == 0 {
+            if self.marker.is_some() || self.pos >= self.data.len() {
+                return Err(From::from("[-] Unexpected end of entropy-coded segment."));
+            }
+            let byte = self.data[self.pos];
+            self.pos += 1;
+            if byte == 0xff {
+                match self.data.get(self.pos) {
+                    Some(0x00) => self.pos += 1,
+                    Some(&m) => {
+                        self.pos += 1;
+                        self.marker = Some(m);
+                        return Err(From::from("[-] Unexpected marker in entropy-coded segment."));
+                    }
+                    None => return Err(From::from("[-] Unexpected end of entropy-coded segment.")),
+                }
+            }
+            self.buffer = byte;
+        }
+        let ret = (self.buffer & (1 << (7 - self.offset))) != 0;
+        self.offset = (self.offset + 1) % 8;
+        Ok(ret)
+    }
+}
+
+/// Writes an entropy-coded segment, stuffing a 0x00 after every 0xFF byte (F.1.2.3)
+#[derive(Default)]
+pub struct SegmentWriter {
+    data: Vec<u8>,
+    buffer: u8,
+    offset: u8,
+}
+
+impl SegmentWriter {
+    pub fn new() -> Self {
+        SegmentWriter::default()
+    }
+
+    /// Pads the last byte with 1-bits and returns the segment
+    pub fn finish(mut self) -> Vec<u8> {
+        while self.offset != 0 {
+            self.write_bit(true).unwrap();
+        }
+        self.data
+    }
+
+    /// Pads the last byte with 1-bits and appends the RSTm marker
+    pub fn restart(&mut self, m: u8) {
+        while self.offset != 0 {
+            self.write_bit(true).unwrap();
+        }
+        self.data.extend_from_slice(&[0xff, 0xd0 + (m & 7)]);
+    }
+}
+
+impl BitWrite for SegmentWriter {
+    fn write_bit(&mut self, bit: bool) -> Result<bool, Box<dyn Error>> {
+        self.buffer = (self.buffer << 1) | bit as u8;
+        self.offset += 1;
+        if self.offset == 8 {
+            self.data.push(self.buffer);
+            if self.buffer == 0xff {
+                self.data.push(0x00);
+            }
+            self.offset = 0;
+            self.buffer = 0;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+/// Number of bits needed for the magnitude of `value` (SSSS category of F.1.2.1)
+fn category(value: i32) -> u8 {
+    (32 - value.unsigned_abs().leading_zeros()) as u8
+}
+
+/// Converts the RECEIVE'd `bits` of a `size` bit magnitude to a signed value (EXTEND of F.2.2.1)
+pub fn extend(bits: u32, size: u8) -> i32 {
+    if size == 0 {
+        return 0;
+    }
+    if bits < 1 << (size - 1) {
+        bits as i32 - (1 << size) + 1
+    } else {
+        bits as i32
+    }
+}
+
+/// Table, symbol, extra magnitude bits and their number
+type BlockSymbol = (TableClass, u8, u32, u8);
+
+/// Huffman symbols of a block in emission order.
+/// Values past the baseline categories are an error, they would corrupt the symbols.
+fn block_symbols(block: &[i32; BLOCK_SIZE], pred: &mut i32) -> Result<Vec<BlockSymbol>, Box<dyn Error>> {
+    let mut ret = Vec::new();
+
+    let diff = block[0].wrapping_sub(*pred);
+    let size = category(diff);
+    if size > MAX_DC_CATEGORY {
+        return Err(From::from(format!("[-] DC difference [{}] does not fit a baseline JPEG category.", diff)));
+    }
+    *pred = block[0];
+    let extra = if diff < 0 { diff - 1 } else { diff } as u32 & ((1u32 << size) - 1);
+    ret.push((TableClass::Dc, size, extra, size));
+
+    let mut run = 0u8;
+    for &coef in &block[1..] {
+        if coef == 0 {
+            run += 1;
+            continue;
+        }
+        // Runs over 15 zeros are sent as ZRL symbols
+        while run > 15 {
+            ret.push((TableClass::Ac, 0xf0, 0, 0));
+            run -= 16;
+        }
+        let size = category(coef);
+        if size > MAX_AC_CATEGORY {
+            return Err(From::from(format!("[-] AC coefficient [{}] does not fit a baseline JPEG category.", coef)));
+        }
+        let extra = if coef < 0 { coef - 1 } else { coef } as u32 & ((1u32 << size) - 1);
+        ret.push((TableClass::Ac, (run << 4) | size, extra, size));
+        run = 0;
+    }
+    if run > 0 {
+        // End of block
+        ret.push((TableClass::Ac, 0x00, 0, 0));
+    }
+
+    Ok(ret)
+}
+
+/// Huffman codes a block of quantized coefficients, in zig-zag order, against the previous DC value `pred`
+pub fn encode_block<W: BitWrite>(writer: &mut W, block: &[i32; BLOCK_SIZE], pred: &mut i32, dc: &[Code], ac: &[Code]) -> Result<(), Box<dyn Error>> {
+    for (class, symbol, extra, size) in block_symbols(block, pred)? {
+        let code = match class {
+            TableClass::Dc => dc[symbol as usize],
+            TableClass::Ac => ac[symbol as usize],
+        };
+        if code.len == 0 {
+            return Err(From::from("[-] Symbol missing from the JPEG Huffman table."));
+        }
+        writer.write_bits(code.bits, code.len)?;
+        writer.write_bits(extra, size)?;
+    }
+    Ok(())
+}
+
+/// Counts the symbols `encode_block` would emit, to feed `HuffmanTable::optimized`
+pub fn count_block(block: &[i32; BLOCK_SIZE], pred: &mut i32, dc_freqs: &mut [u64; 256], ac_freqs: &mut [u64; 256]) -> Result<(), Box<dyn Error>> {
+    for (class, symbol, _, _) in block_symbols(block, pred)? {
+        match class {
+            TableClass::Dc => dc_freqs[symbol as usize] += 1,
+            TableClass::Ac => ac_freqs[symbol as usize] += 1,
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a block of quantized coefficients, in zig-zag order (F.2.2.1 and F.2.2.2)
+pub fn decode_block<R: BitRead>(reader: &mut R, pred: &mut i32, dc: &CanonicalCode, ac: &CanonicalCode) -> Result<[i32; BLOCK_SIZE], Box<dyn Error>> {
+    let mut block = [0i32; BLOCK_SIZE];
+
+    let size = dc.decode(reader)? as u8;
+    if size > MAX_DC_CATEGORY {
+        return Err(From::from("[-] Invalid DC difference category."));
+    }
+    *pred = pred.checked_add(extend(reader.read_bits(size)?, size)).ok_or("[-] DC value out of range.")?;
+    block[0] = *pred;
+
+    let mut k = 1;
+    while k < BLOCK_SIZE {
+        let rs = ac.decode(reader)? as u8;
+        let (run, size) = ((rs >> 4) as usize, rs & 0x0f);
+        if size == 0 {
+            if run != 15 {
+                // End of block
+                break;
+            }
+            // ZRL: 16 zeros, some coefficient must follow them
+            k += 16;
+            if k >= BLOCK_SIZE {
+                return Err(From::from("[-] Zero run past the end of the block."));
+            }
+            continue;
+        }
+        if size > MAX_AC_CATEGORY {
+            return Err(From::from("[-] Invalid AC coefficient category."));
+        }
+        k += run;
+        if k >= BLOCK_SIZE {
+            return Err(From::from("[-] AC coefficients overflow the block."));
+        }
+        block[k] = extend(reader.read_bits(size)?, size);
+        k += 1;
+    }
+
+    Ok(block)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Table K.3: luminance DC differences
+    fn luminance_dc() -> HuffmanTable {
+        HuffmanTable::new([0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0], (0..12).collect()).unwrap()
+    }
+
+    fn sample_blocks() -> Vec<[i32; BLOCK_SIZE]> {
+        let mut blocks = Vec::new();
+        for i in 0..20i32 {
+            let mut block = [0i32; BLOCK_SIZE];
+            block[0] = 60 - i * 7;
+            block[1] = i - 10;
+            block[2] = (i % 3) - 1;
+            block[5] = 255;
+            // Long zero runs exercise ZRL, a last coefficient exercises the missing EOB
+            block[40] = -(i % 4);
+            block[63] = i % 2;
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn check_standard_dc_codes() -> Result<(), Box<dyn Error>> {
+        let codes = luminance_dc().codes()?;
+        assert_eq!(codes[0], Code { bits: 0b00, len: 2 });
+        assert_eq!(codes[1], Code { bits: 0b010, len: 3 });
+        assert_eq!(codes[5], Code { bits: 0b110, len: 3 });
+        assert_eq!(codes[6], Code { bits: 0b1110, len: 4 });
+        assert_eq!(codes[11], Code { bits: 0b111111110, len: 9 });
+
+        let dht = luminance_dc().to_dht(TableClass::Dc, 0);
+        let tables = HuffmanTable::parse_dht(&dht[4..])?;
+        assert_eq!(tables, vec![(TableClass::Dc, 0, luminance_dc())]);
+
+        // 4 codes of length 2 use up all the code space
+        assert!(HuffmanTable::new([0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], vec![0, 1, 2, 3]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_blocks_round_trip() -> Result<(), Box<dyn Error>> {
+        let blocks = sample_blocks();
+
+        // Optimize both tables on the data
+        let (mut dc_freqs, mut ac_freqs) = ([0u64; 256], [0u64; 256]);
+        let mut pred = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            if i == 10 {
+                pred = 0;
+            }
+            count_block(block, &mut pred, &mut dc_freqs, &mut ac_freqs)?;
+        }
+        let dc = HuffmanTable::optimized(&dc_freqs);
+        let ac = HuffmanTable::optimized(&ac_freqs);
+        assert!(HuffmanTable::new(dc.bits, dc.huffval.clone()).is_ok());
+        assert!(HuffmanTable::new(ac.bits, ac.huffval.clone()).is_ok());
+
+        let mut writer = SegmentWriter::new();
+        let (dc_codes, ac_codes) = (dc.codes()?, ac.codes()?);
+        let mut pred = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            if i == 10 {
+                writer.restart(0);
+                pred = 0;
+            }
+            encode_block(&mut writer, block, &mut pred, &dc_codes, &ac_codes)?;
+        }
+        let segment = writer.finish();
+        assert!(segment.windows(2).all(|w| w[0] != 0xff || w[1] == 0x00 || w[1] == 0xd0));
+
+        let mut reader = SegmentReader::new(&segment);
+        let (dc_code, ac_code) = (dc.code()?, ac.code()?);
+        let mut pred = 0;
+        for (i, block) in blocks.iter().enumerate() {
+            if i == 10 {
+                assert_eq!(reader.restart()?, 0);
+                pred = 0;
+            }
+            assert_eq!(&decode_block(&mut reader, &mut pred, &dc_code, &ac_code)?, block);
+        }
+        assert_eq!(reader.position(), segment.len());
+
+        // Out of the baseline categories
+        let mut block = [0i32; BLOCK_SIZE];
+        block[0] = 2048;
+        let mut pred = 0;
+        assert!(count_block(&block, &mut pred, &mut dc_freqs, &mut ac_freqs).is_err());
+        assert_eq!(pred, 0);
+        block[0] = 2047;
+        block[5] = -1024;
+        assert!(encode_block(&mut SegmentWriter::new(), &block, &mut pred, &dc_codes, &ac_codes).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_corrupt_blocks() -> Result<(), Box<dyn Error>> {
+        // AC codes 00 for end of block, 01 for ZRL and 10 for a single 1 bit coefficient
+        let ac = HuffmanTable::new([0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], vec![0x00, 0xf0, 0x01])?;
+        let (dc, ac_code) = (luminance_dc(), ac.code()?);
+        let (dc_codes, ac_codes) = (dc.codes()?, ac.codes()?);
+        let decode = |symbols: &[(Code, u32, u8)], pred: &mut i32| {
+            let mut writer = SegmentWriter::new();
+            for &(code, extra, size) in symbols {
+                writer.write_bits(code.bits, code.len)?;
+                writer.write_bits(extra, size)?;
+            }
+            let segment = writer.finish();
+            decode_block(&mut SegmentReader::new(&segment), pred, &dc.code()?, &ac_code)
+        };
+
+        // A DC difference past the range of the prediction
+        let mut pred = i32::MAX;
+        assert!(decode(&[(dc_codes[1], 1, 1), (ac_codes[0x00], 0, 0)], &mut pred).is_err());
+
+        // Three ZRL reach coefficient 49, a fourth one runs past the block
+        let mut symbols = vec![(dc_codes[0], 0, 0); 1];
+        symbols.extend([(ac_codes[0xf0], 0, 0); 3]);
+        symbols.push((ac_codes[0x01], 1, 1));
+        symbols.push((ac_codes[0x00], 0, 0));
+        let mut pred = 0;
+        assert_eq!(decode(&symbols, &mut pred)?[49], 1);
+        symbols.insert(4, (ac_codes[0xf0], 0, 0));
+        assert!(decode(&symbols, &mut pred).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_optimized_limit() {
+        // Very skewed statistics would need codes longer than 16 bits
+        let mut freqs = [0u64; 256];
+        let mut weight = 1u64;
+        for f in freqs.iter_mut().take(40) {
+            *f = weight;
+            weight = weight * 3 / 2 + 1;
+        }
+        let table = HuffmanTable::optimized(&freqs);
+        assert_eq!(table.huffval.len(), 40);
+        assert!(table.bits[15] > 0);
+        assert!(HuffmanTable::new(table.bits, table.huffval).is_ok());
+    }
+}
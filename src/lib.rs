@@ -1 +1,13 @@
-pub mod file_bin;
\ No newline at end of file
+pub mod bzip2;
+pub mod canonical;
+pub mod code;
+pub mod crc32;
+pub mod dictionary;
+pub mod file_bin;
+pub mod fse;
+pub mod huff0;
+pub mod jpeg;
+pub mod range;
+pub mod transform;
+pub mod utf8;
+pub mod words;
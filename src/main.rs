@@ -1,77 +1,857 @@
 
+mod archive;
 mod huffman;
 use std::error::Error;
-use std::path::Path;
-use clap::{Arg, App};
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let matches = App::new("Rust Huffman Compression")
-                          .version("1.0")
-                          .author("User420")
-                          .arg(Arg::with_name("mode")
-                            .short("m")
-                            .long("mode")
-                            .takes_value(true)
-                            .help("Action to perform")
-                            .required(true))
-                          .arg(Arg::with_name("in_path")
-                            .short("i")
-                            .long("inpath")
-                            .value_name("FILE")
-                            .help("The path of the input file to process")
-                            .required(true)
-                            .takes_value(true))
-                          .arg(Arg::with_name("out_path")
-                            .help("The path of the output")
-                            .index(1))
-                          .arg(Arg::with_name("verbose")
-                            .short("v")
-                            .long("verbose")
-                            .takes_value(false)
-                            .help("Sets verbosity on"))
-                          .arg(Arg::with_name("N")
-                            .help("Number of iterations"))
-                          .get_matches();
-
-    // Gets a value for config if supplied by user, or defaults to "default.conf"
-    let iterations = matches.value_of("N").unwrap_or("1").parse::<u64>().unwrap();
-
-    let path_in = String::from(matches.value_of("in_path").unwrap());
-    // Build the out path
-    let mut path_out = match matches.is_present("out_path") {
-        true => String::from(matches.value_of("out_path").unwrap()),
-        false => {
-            let raw = Path::new(&path_in);
-            let folder = raw.parent().unwrap();
-            let file_stem = raw.file_stem().unwrap();
-            let path = folder.join(file_stem.to_str().unwrap());
-
-            let str_path = String::from(path.to_str().unwrap());
-            str_path
+use std::fs::File;
+use std::io::{BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Writes the diagnostics of the library to stderr, prefixed by their level
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let prefix = match record.level() {
+                Level::Error | Level::Warn => "[-]",
+                Level::Info => "[+]",
+                Level::Debug | Level::Trace => "[=]",
+            };
+            eprintln!("{} {}", prefix, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Progress bar on stderr for an input of `total` bytes, with throughput and time left
+fn progress_bar(total: u64) -> huffman::Progress {
+    let start = Instant::now();
+    let done = AtomicBool::new(false);
+    huffman::Progress::new(0, move |bytes_in, _| {
+        // The last block and the end of the file both report the whole input
+        if done.load(Ordering::Relaxed) {
+            return;
+        }
+        let ratio = match total {
+            0 => 1.0,
+            _ => (bytes_in as f64 / total as f64).min(1.0),
+        };
+        let seconds = start.elapsed().as_secs_f64();
+        let speed = bytes_in as f64 / seconds.max(1e-6);
+        let eta = (total.saturating_sub(bytes_in)) as f64 / speed.max(1.0);
+        let width = 30;
+        let filled = (ratio * width as f64) as usize;
+        eprint!("\r[=] [{}{}] {:>3.0}% {:>8.2} MiB/s ETA {:.0} s ",
+                "#".repeat(filled), ".".repeat(width - filled), ratio * 100.0, speed / (1 << 20) as f64, eta);
+        if bytes_in >= total {
+            done.store(true, Ordering::Relaxed);
+            eprintln!();
+        }
+        std::io::stderr().flush().ok();
+    })
+}
+
+/// Suffix of the compressed files unless --suffix is given
+const SUFFIX: &str = ".huff";
+/// Suffix of the bzip2 files, also removed by decompress
+const BZIP2_SUFFIX: &str = ".bz2";
+/// Path standing for stdin or stdout
+const STDIO: &str = "-";
+/// Exit status of an error, such as an input that cannot be read
+const EXIT_ERROR: i32 = 1;
+/// Exit status of a file that failed the integrity test
+const EXIT_CORRUPT: i32 = 2;
+/// Exit status of a run stopped by Ctrl-C, as shells report SIGINT
+const EXIT_CANCELLED: i32 = 130;
+
+/// Arguments of the subcommands that take one input file
+fn input<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .value_name("FILE")
+        .help("The file to process")
+        .required(true)
+        .index(1)
+}
+
+/// Input of the subcommands that can read stdin
+fn input_or_stdin<'a, 'b>() -> Arg<'a, 'b> {
+    input().required(false).default_value(STDIO).help("The file to process, - for stdin")
+}
+
+fn output<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("The path of the output, - for stdout, derived from the input by default")
+}
+
+fn stdout<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("stdout")
+        .short("c")
+        .long("stdout")
+        .conflicts_with("output")
+        .help("Writes to stdout, the default when reading stdin")
+}
+
+/// Arguments of the subcommands that replace files by their output
+fn batch<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("output_dir")
+            .long("output-dir")
+            .value_name("DIR")
+            .takes_value(true)
+            .conflicts_with_all(&["output", "stdout"])
+            .help("Writes the outputs in this directory, mirroring the directories given with -r"),
+        Arg::with_name("recursive")
+            .short("r")
+            .long("recursive")
+            .help("Processes the files in the given directories and their subdirectories"),
+        Arg::with_name("keep")
+            .short("k")
+            .long("keep")
+            .help("Keeps the input files, removed once their output is written otherwise"),
+        Arg::with_name("force")
+            .short("f")
+            .long("force")
+            .help("Overwrites existing output files"),
+        Arg::with_name("suffix")
+            .short("S")
+            .long("suffix")
+            .value_name("SUF")
+            .takes_value(true)
+            .validator(|v| match v.is_empty() || v.contains(std::path::is_separator) {
+                true => Err(String::from("must be a non empty file name suffix")),
+                false => Ok(()),
+            })
+            .help("Suffix of the compressed files, .huff by default"),
+    ]
+}
+
+fn archive<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("archive")
+        .index(1)
+        .required(true)
+        .help("The archive")
+}
+
+fn dictionary<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("dictionary")
+        .long("dict")
+        .value_name("FILE")
+        .takes_value(true)
+        .help("Dictionary made by the train subcommand, used instead of a tree per block")
+}
+
+/// Arguments of the original file kept in the header
+fn metadata<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("no_name")
+            .short("n")
+            .long("no-name")
+            .help("Neither stores nor restores the original file name"),
+        Arg::with_name("no_preserve")
+            .long("no-preserve")
+            .help("Neither stores nor restores the permissions and modification time"),
+    ]
+}
+
+/// Arguments choosing how blocks are coded
+fn coding<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("transform")
+            .short("t")
+            .long("transform")
+            .help("Applies the BWT, move-to-front and run length transform before compressing"),
+        Arg::with_name("tables")
+            .long("tables")
+            .value_name("NB")
+            .takes_value(true)
+            .validator(|v| match v.parse::<usize>() {
+                Ok(n) if (1..=huffman::MAX_TABLES).contains(&n) => Ok(()),
+                _ => Err(format!("must be a number between 1 and {}", huffman::MAX_TABLES)),
+            })
+            .help("Maximum number of Huffman tables per block, picked for each segment"),
+        Arg::with_name("entropy")
+            .long("entropy")
+            .takes_value(true)
+            .possible_values(&["huffman", "ans", "range"])
+            .help("Entropy coder of the blocks"),
+        Arg::with_name("alphabet")
+            .long("alphabet")
+            .takes_value(true)
+            .possible_values(&["bytes", "words", "utf8"])
+            .help("Symbols to code"),
+    ]
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Rust Huffman Compression")
+        .version("1.0")
+        .author("User420")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .after_help("EXIT STATUS:\n    0    Success\n    1    Error, invalid arguments or an unreadable input\n    2    A tested file is corrupt\n    130  Interrupted")
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .multiple(true)
+            .global(true)
+            .help("Logs the steps to stderr, repeat for block sizes (-vv) and trees (-vvv)"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .global(true)
+            .possible_values(&["text", "json"])
+            .help("Format of the results, on stdout unless the data goes there, the progress always goes to stderr"))
+        .subcommand(SubCommand::with_name("compress")
+            .about("Compresses files, replacing each FILE by FILE.huff")
+            .arg(input_or_stdin().multiple(true))
+            .arg(output())
+            .arg(stdout())
+            .args(&batch())
+            .args(&metadata())
+            .args(&coding())
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("decompress")
+            .about("Decompresses files made by compress, an older version or bzip2")
+            .arg(input_or_stdin().multiple(true))
+            .arg(output())
+            .arg(stdout())
+            .args(&batch())
+            .args(&metadata())
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("test")
+            .about("Decodes files without writing anything, checking their header, trees, length and checksum")
+            .arg(input_or_stdin().multiple(true))
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("list")
+            .about("Lists the blocks of a compressed file")
+            .arg(input_or_stdin())
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("stats")
+            .about("Shows the byte statistics of a file and the size of its Huffman coding")
+            .arg(input()))
+        .subcommand(SubCommand::with_name("dump-tree")
+            .about("Shows the code of every byte in the Huffman trees of a compressed file")
+            .arg(input_or_stdin())
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Times compressing and decompressing a file")
+            .arg(input())
+            .arg(Arg::with_name("iterations")
+                .short("n")
+                .long("iterations")
+                .value_name("N")
+                .takes_value(true)
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from("must be a positive number")),
+                })
+                .help("Number of runs, 1 by default"))
+            .args(&coding())
+            .arg(dictionary()))
+        .subcommand(SubCommand::with_name("train")
+            .about("Builds a dictionary from sample files")
+            .arg(input().multiple(true))
+            .arg(output()))
+        .subcommand(SubCommand::with_name("archive")
+            .about("Packs many files into one archive")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("Compresses files into an archive, created if missing, replacing the entries of the same path")
+                .arg(archive())
+                .arg(Arg::with_name("files")
+                    .index(2)
+                    .required(true)
+                    .multiple(true)
+                    .help("The files to add"))
+                .arg(Arg::with_name("recursive")
+                    .short("r")
+                    .long("recursive")
+                    .help("Adds the files in the given directories and their subdirectories"))
+                .arg(Arg::with_name("shared")
+                    .long("shared")
+                    .help("Codes the entries of a new archive with one set of tables trained on the files"))
+                .args(&coding()))
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists the entries of an archive")
+                .arg(archive()))
+            .subcommand(SubCommand::with_name("extract")
+                .about("Extracts the entries of an archive, all of them or the given paths")
+                .arg(archive())
+                .arg(Arg::with_name("paths")
+                    .index(2)
+                    .multiple(true)
+                    .help("Entries or directories to extract"))
+                .arg(Arg::with_name("directory")
+                    .short("C")
+                    .long("directory")
+                    .value_name("DIR")
+                    .takes_value(true)
+                    .help("Extracts into this directory instead of the current one"))
+                .arg(Arg::with_name("force")
+                    .short("f")
+                    .long("force")
+                    .help("Overwrites existing files"))))
+}
+
+/// Settings of the subcommand, those it does not take keep their default
+fn options(matches: &ArgMatches) -> Result<huffman::Options, Box<dyn Error>> {
+    Ok(huffman::Options {
+        transform: matches.is_present("transform"),
+        tables: matches.value_of("tables").unwrap_or("1").parse::<usize>()?,
+        entropy: match matches.value_of("entropy") {
+            Some("ans") => huffman::Entropy::Ans,
+            Some("range") => huffman::Entropy::Range,
+            _ => huffman::Entropy::Huffman,
+        },
+        alphabet: match matches.value_of("alphabet") {
+            Some("words") => huffman::Alphabet::Words,
+            Some("utf8") => huffman::Alphabet::Utf8,
+            _ => huffman::Alphabet::Bytes,
+        },
+        dictionary: match matches.value_of("dictionary") {
+            Some(path) => Some(huffman::Dictionary::load(path)?),
+            None => None,
+        },
+        ..Default::default()
+    })
+}
+
+/// The path without its extension and with the given one
+fn derived_path(path: &Path, extension: &str) -> String {
+    let path: PathBuf = path.parent().unwrap().join(path.file_stem().unwrap());
+    format!("{}{}", path.to_str().unwrap(), extension)
+}
+
+/// Output given with -o, or derived from the first input
+fn output_path(matches: &ArgMatches, extension: &str) -> String {
+    match matches.value_of("output") {
+        Some(path) => String::from(path),
+        None => derived_path(Path::new(matches.value_of("input").unwrap()), extension),
+    }
+}
+
+/// Output of an input like gzip names it: the suffix is added when compressing, and removed when
+/// decompressing unless the name stored in the header replaces the whole file name
+fn output_name(path: &Path, compress: bool, suffix: &str, stored: Option<String>) -> Result<PathBuf, Box<dyn Error>> {
+    let name = path.file_name().and_then(|name| name.to_str()).ok_or("[-] Not a file name.")?;
+    if compress {
+        if name.ends_with(suffix) {
+            return Err(From::from(format!("[-] Already has the {} suffix.", suffix)));
+        }
+        return Ok(path.with_file_name(format!("{}{}", name, suffix)));
+    }
+    if let Some(stored) = stored.as_deref().and_then(|stored| Path::new(stored).file_name()) {
+        return Ok(path.with_file_name(stored));
+    }
+    match [suffix, BZIP2_SUFFIX].iter().find_map(|suffix| name.strip_suffix(suffix)) {
+        Some(stem) if !stem.is_empty() => Ok(path.with_file_name(stem)),
+        _ => Err(From::from(format!("[-] Unknown suffix, expected {}, use -o or --suffix.", suffix))),
+    }
+}
+
+/// Files of a directory and its subdirectories, sorted, with their path under `root`.
+/// Symbolic links to directories are not followed.
+fn walk(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, PathBuf)>) -> Result<(), Box<dyn Error>> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, files)?;
+        } else if path.is_file() {
+            files.push((path.clone(), path.strip_prefix(root)?.to_path_buf()));
+        }
+    }
+    Ok(())
+}
+
+/// Compresses or decompresses every input, errors are reported per file and do not stop the others
+fn run(matches: &ArgMatches, compress: bool, options: huffman::Options, cancel: &huffman::CancelToken, json: bool) -> Result<i32, Box<dyn Error>> {
+    let paths: Vec<&str> = matches.values_of("input").unwrap().collect();
+    if paths.len() > 1 || matches.is_present("recursive") {
+        if matches.value_of("output").is_some_and(|path| path != STDIO) {
+            return Err(From::from("[-] -o takes a single input file, use --output-dir."));
         }
+        if compress && (matches.is_present("stdout") || matches.value_of("output") == Some(STDIO)) {
+            return Err(From::from("[-] A single input can be compressed to stdout."));
+        }
+    }
+
+    let suffix = matches.value_of("suffix").unwrap_or(SUFFIX);
+    let mut code = 0;
+    let mut fail = |path: &Path, e: Box<dyn Error>| {
+        eprintln!("[-] [{}]: {}", path.display(), e.to_string().trim_start_matches("[-] "));
+        code = 1;
     };
+    // Input files with their path under the output directory
+    let mut files = Vec::new();
+    for path in paths.iter().map(Path::new) {
+        if !path.is_dir() {
+            files.push((path.to_path_buf(), PathBuf::from(path.file_name().unwrap_or_default())));
+        } else if !matches.is_present("recursive") {
+            fail(path, From::from("Is a directory, use -r."));
+        } else {
+            let mut found = Vec::new();
+            match walk(path, path, &mut found) {
+                // Only compressed files get decompressed, and the other way around
+                Ok(()) => files.extend(found.into_iter().filter(|(file, _)| {
+                    file.to_str().is_some_and(|file| file.ends_with(suffix)) != compress
+                })),
+                Err(e) => fail(path, e),
+            }
+        }
+    }
+
+    for (path_in, relative) in files {
+        match run_file(matches, compress, &path_in, &relative, options.clone(), cancel, json) {
+            Ok(()) => {},
+            Err(e) if e.is::<huffman::Cancelled>() => return Err(e),
+            Err(e) => fail(&path_in, e),
+        }
+    }
+    Ok(code)
+}
 
-    let mut compress = false;
-    match matches.value_of("mode").unwrap() {
-        "c" | "compress" => {
-            compress = true;
-            path_out.push_str(".huff");
+/// Compresses or decompresses a single input, with a progress bar on terminals
+fn run_file(matches: &ArgMatches, compress: bool, path_in: &Path, relative: &Path, mut options: huffman::Options, cancel: &huffman::CancelToken, json: bool) -> Result<(), Box<dyn Error>> {
+    let path_in = String::from(path_in.to_str().ok_or("[-] Path is not valid UTF-8.")?);
+    // Reading stdin writes to stdout unless an output is given, like gzip
+    let to_stdout = matches.is_present("stdout") || match matches.value_of("output") {
+        Some(path) => path == STDIO,
+        None => path_in == STDIO,
+    };
+    if compress && path_in != STDIO {
+        let origin = huffman::Origin::of(Path::new(&path_in))?;
+        options.origin = Some(huffman::Origin {
+            name: origin.name.filter(|_| !matches.is_present("no_name")),
+            mode: origin.mode.filter(|_| !matches.is_present("no_preserve")),
+            mtime: origin.mtime.filter(|_| !matches.is_present("no_preserve")),
+        });
+    }
+    let path_out = match (to_stdout, matches.value_of("output")) {
+        (true, _) => String::from(STDIO),
+        (false, Some(path)) => String::from(path),
+        (false, None) => {
+            let stored = match compress || matches.is_present("no_name") {
+                true => None,
+                false => huffman::origin(&path_in)?.and_then(|origin| origin.name),
+            };
+            let path = match matches.value_of("output_dir") {
+                Some(dir) => Path::new(dir).join(relative),
+                None => PathBuf::from(&path_in),
+            };
+            let suffix = matches.value_of("suffix").unwrap_or(SUFFIX);
+            String::from(output_name(&path, compress, suffix, stored)?.to_str().unwrap())
         },
-        "d" | "decompress" => {
-            path_out.push_str(".dhuff");
+    };
+    if compress && to_stdout && std::io::stdout().is_terminal() {
+        return Err(From::from("[-] Compressed data is not written to a terminal, redirect stdout or use -o."));
+    }
+    if path_out != STDIO {
+        if path_out == path_in {
+            return Err(From::from(format!("[-] [{}] would be overwritten by its own output.", path_out)));
         }
-        _ => panic!("Invalid mode"),
+        if Path::new(&path_out).exists() && !matches.is_present("force") {
+            return Err(From::from(format!("[-] [{}] already exists, use --force to overwrite it.", path_out)));
+        }
+        if let Some(dir) = Path::new(&path_out).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    if path_in != STDIO && std::io::stderr().is_terminal() {
+        options.progress = Some(progress_bar(std::fs::metadata(&path_in)?.len()));
+    }
+    options.cancel = Some(cancel.clone());
+    let start = Instant::now();
+    let report = match (path_in == STDIO || to_stdout, compress) {
+        (false, true) => huffman::compress(&path_in, &path_out, &options)?,
+        (false, false) => huffman::decompress(&path_in, &path_out, &options)?,
+        (true, _) => stream(&path_in, &path_out, compress, &options)?,
     };
+    let seconds = start.elapsed().as_secs_f64();
+    if let (Some(origin), false) = (&report.origin, path_out == STDIO || matches.is_present("no_preserve")) {
+        origin.restore(Path::new(&path_out))?;
+    }
+    let summary = match json {
+        true => report.to_json(compress, &path_in, &path_out, seconds),
+        false => format!("[+] [{}] -> [{}]: [{}] -> [{}] bytes, ratio [{:.2}%], crc32 [{:08x}], [{:.3}] s",
+                         path_in, path_out, report.size_in, report.size_out, report.ratio(compress) * 100.0, report.checksum, seconds),
+    };
+    // Keeps stdout for the data when it goes there
+    match to_stdout {
+        true => eprintln!("{}", summary),
+        false => println!("{}", summary),
+    }
+    // Like gzip, the input is replaced by the output
+    if !matches.is_present("keep") && !to_stdout && path_in != STDIO {
+        std::fs::remove_file(&path_in)?;
+    }
+    Ok(())
+}
 
-    for i in 0..iterations {
-        path_out.push_str(&i.to_string());
-        if compress {
-            huffman::compress(&path_in, &path_out)?;
+/// Compresses or decompresses with `-` standing for stdin or stdout
+fn stream(path_in: &str, path_out: &str, compress: bool, options: &huffman::Options) -> Result<huffman::Report, Box<dyn Error>> {
+    let reader: Box<dyn Read> = match path_in {
+        STDIO => Box::new(std::io::stdin().lock()),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let writer: Box<dyn Write> = match path_out {
+        STDIO => Box::new(BufWriter::new(std::io::stdout().lock())),
+        path => Box::new(BufWriter::new(File::create(path)?)),
+    };
+    let report = match compress {
+        true => huffman::compress_stream(reader, writer, path_out, options),
+        false => huffman::decompress_stream(reader, writer, path_in, options),
+    };
+    // Partial outputs are removed, as the library does for files
+    if report.is_err() && path_out != STDIO {
+        std::fs::remove_file(path_out)?;
+    }
+    report
+}
+
+/// Decodes a file or stdin without keeping the data
+fn check(path: &str, options: &huffman::Options) -> Result<huffman::Report, Box<dyn Error>> {
+    let report = match path {
+        STDIO => huffman::decompress_stream(std::io::stdin().lock(), std::io::sink(), path, options),
+        path => huffman::decompress_to(&String::from(path), std::io::sink(), options),
+    };
+    // The data ran out before the end block
+    report.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == std::io::ErrorKind::UnexpectedEof => From::from("[-] Unexpected end of file, truncated."),
+        _ => e,
+    })
+}
+
+/// Decodes every input without writing it, corrupt or unreadable files do not stop the others.
+/// A corrupt file sets the exit status to EXIT_CORRUPT, an unreadable one to EXIT_ERROR unless a
+/// file was found corrupt.
+fn test(matches: &ArgMatches, options: &huffman::Options, json: bool) -> Result<i32, Box<dyn Error>> {
+    let mut code = 0;
+    for path in matches.values_of("input").unwrap() {
+        let result = check(path, options);
+        // Truncated data is reported by check() as corrupt, other I/O errors come from the input itself
+        let unreadable = result.as_ref().err().is_some_and(|e| e.is::<std::io::Error>());
+        if let Err(e) = &result {
+            if e.is::<huffman::Cancelled>() {
+                return Err(result.unwrap_err());
+            }
+            code = code.max(if unreadable { EXIT_ERROR } else { EXIT_CORRUPT });
+        }
+        match (json, result) {
+            (true, Ok(report)) => println!("{{\"input\":{},\"ok\":true,\"verified\":{},\"size\":{},\"checksum\":\"{:08x}\"}}",
+                                           huffman::json_string(path), report.verified, report.size_out, report.checksum),
+            (true, Err(e)) => println!("{{\"input\":{},\"ok\":false,\"error\":{}}}", huffman::json_string(path), huffman::json_string(&message(&*e))),
+            (false, Ok(report)) => println!("[+] [{}]: OK, [{}] bytes, crc32 [{:08x}]{}", path, report.size_out, report.checksum,
+                                            if report.verified { "" } else { ", no stored checksum to compare" }),
+            (false, Err(e)) if unreadable => eprintln!("[-] [{}]: cannot read, {}", path, e),
+            (false, Err(e)) => println!("[-] [{}]: corrupt, {}", path, e.to_string().trim_start_matches("[-] ")),
+        }
+    }
+    Ok(code)
+}
+
+/// Blocks of a compressed file, with the code tables for dump-tree
+fn list(matches: &ArgMatches, options: &huffman::Options, tables: bool, json: bool) -> Result<i32, Box<dyn Error>> {
+    let path = matches.value_of("input").unwrap();
+    let report = check(path, options)?;
+    if json {
+        let blocks: Vec<String> = report.block_list.iter().map(|block| block.to_json(tables)).collect();
+        let origin = match &report.origin {
+            Some(origin) => format!("{{\"name\":{},\"mode\":{},\"mtime\":{}}}",
+                                    origin.name.as_deref().map_or(String::from("null"), huffman::json_string),
+                                    origin.mode.map_or(String::from("null"), |mode| mode.to_string()),
+                                    origin.mtime.map_or(String::from("null"), |mtime| mtime.to_string())),
+            None => String::from("null"),
+        };
+        println!("{{\"input\":{},\"format\":{},\"size\":{},\"compressed\":{},\"origin\":{},\"blocks\":[{}]}}",
+                 huffman::json_string(path), huffman::json_string(&report.format.to_string()), report.size_out, report.size_in, origin, blocks.join(","));
+        return Ok(0);
+    }
+    println!("[+] [{}]: {}, [{}] -> [{}] bytes", path, report.format, report.size_in, report.size_out);
+    if let Some(origin) = &report.origin {
+        println!("[=] original {} {} {}", origin.mode.map_or(String::from("----"), |mode| format!("{:04o}", mode)),
+                 origin.mtime.map_or(String::from("-"), utc), origin.name.as_deref().unwrap_or("-"));
+    }
+    for (i, block) in report.block_list.iter().enumerate() {
+        println!("[=] block {:>4} {:<10} {:>8} -> {:>8} bytes{}", i, block.name(), block.size, block.compressed,
+                 if block.transform { ", transformed" } else { "" });
+        if tables {
+            for (j, table) in block.tables.iter().enumerate() {
+                println!("[=]   table {}", j);
+                for (byte, code) in table {
+                    println!("[=]     {:#04x} {:>2} {}", byte, code.len(), code);
+                }
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Compresses and decompresses the input in the temporary directory, checking the round trip
+fn bench(matches: &ArgMatches, mut options: huffman::Options, cancel: &huffman::CancelToken, json: bool) -> Result<i32, Box<dyn Error>> {
+    let path_in = String::from(matches.value_of("input").unwrap());
+    let iterations = matches.value_of("iterations").unwrap_or("1").parse::<u32>()?;
+    let path_huff = String::from(std::env::temp_dir().join(format!("huffman_bench_{}.huff", std::process::id())).to_str().unwrap());
+    options.cancel = Some(cancel.clone());
+
+    let (mut compress, mut decompress) = (0.0, 0.0);
+    let mut report = huffman::Report::default();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        report = huffman::compress(&path_in, &path_huff, &options)?;
+        compress += start.elapsed().as_secs_f64();
+        let start = Instant::now();
+        let check = huffman::decompress_to(&path_huff, std::io::sink(), &options);
+        decompress += start.elapsed().as_secs_f64();
+        if check.as_ref().map(|c| c.checksum).ok() != Some(report.checksum) {
+            std::fs::remove_file(&path_huff)?;
+            return Err(From::from("[-] The decompressed data differs from the input."));
+        }
+    }
+    std::fs::remove_file(&path_huff)?;
+
+    let mib = report.size_in as f64 / (1 << 20) as f64 * iterations as f64;
+    let (compress_speed, decompress_speed) = (mib / compress.max(1e-9), mib / decompress.max(1e-9));
+    match json {
+        true => println!("{{\"input\":{},\"iterations\":{},\"size_in\":{},\"size_out\":{},\"ratio\":{},\"compress_mib_s\":{},\"decompress_mib_s\":{}}}",
+                         huffman::json_string(&path_in), iterations, report.size_in, report.size_out, report.ratio(true), compress_speed, decompress_speed),
+        false => println!("[+] [{}]: [{}] -> [{}] bytes, ratio [{:.2}%], compress [{:.2}] MiB/s, decompress [{:.2}] MiB/s over [{}] runs",
+                          path_in, report.size_in, report.size_out, report.ratio(true) * 100.0, compress_speed, decompress_speed, iterations),
+    }
+    Ok(0)
+}
+
+/// Adds files and directories to an archive, created with shared tables if asked
+fn archive_add(matches: &ArgMatches, mut options: huffman::Options, cancel: &huffman::CancelToken) -> Result<i32, Box<dyn Error>> {
+    let path = matches.value_of("archive").unwrap();
+    options.cancel = Some(cancel.clone());
+    // Files with their path in the archive, directories keep their name
+    let mut files = Vec::new();
+    for file in matches.values_of("files").unwrap().map(Path::new) {
+        let name = PathBuf::from(file.file_name().ok_or(format!("[-] [{}]: Not a file.", file.display()))?);
+        if !file.is_dir() {
+            files.push((file.to_path_buf(), name));
+        } else if !matches.is_present("recursive") {
+            return Err(From::from(format!("[-] [{}]: Is a directory, use -r.", file.display())));
         } else {
-            huffman::decompress(&path_in, &path_out)?;
+            let mut found = Vec::new();
+            walk(file, file, &mut found)?;
+            files.extend(found.into_iter().map(|(path, relative)| (path, name.join(relative))));
         }
     }
 
-    Ok(())
+    let mut archive = match Path::new(path).exists() {
+        true => {
+            if matches.is_present("shared") {
+                log::warn!("The archive exists, its tables are kept.");
+            }
+            archive::Archive::open(path, true)?
+        },
+        false => match matches.is_present("shared") {
+            true => archive::Archive::create(path, Some(huffman::train_dictionary(&files.iter().map(|(p, _)| p).collect::<Vec<_>>())?))?,
+            false => archive::Archive::create(path, None)?,
+        },
+    };
+    for (file, name) in files {
+        let name: Vec<&str> = name.components().filter_map(|c| c.as_os_str().to_str()).collect();
+        let entry = archive.add(&file, &name.join("/"), &options)?;
+        println!("[+] [{}]: [{}] -> [{}] bytes", entry.path, entry.size, entry.compressed);
+    }
+    Ok(0)
+}
+
+/// Date and time of a Unix timestamp, in UTC
+fn utc(timestamp: i64) -> String {
+    let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    // Civil date of a day count, from Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Entries of an archive
+fn archive_list(matches: &ArgMatches, json: bool) -> Result<i32, Box<dyn Error>> {
+    let path = matches.value_of("archive").unwrap();
+    let archive = archive::Archive::open(path, false)?;
+    if json {
+        let entries: Vec<String> = archive.entries().iter().map(|entry| {
+            format!("{{\"path\":{},\"size\":{},\"compressed\":{},\"offset\":{},\"mode\":{},\"mtime\":{},\"checksum\":\"{:08x}\"}}",
+                    huffman::json_string(&entry.path), entry.size, entry.compressed, entry.offset, entry.mode, entry.mtime, entry.checksum)
+        }).collect();
+        println!("{{\"archive\":{},\"shared\":{},\"entries\":[{}]}}", huffman::json_string(path), archive.dictionary().is_some(), entries.join(","));
+        return Ok(0);
+    }
+    println!("[+] [{}]: [{}] entries{}", path, archive.entries().len(), if archive.dictionary().is_some() { ", shared tables" } else { "" });
+    for entry in archive.entries() {
+        println!("[=] {:04o} {:>10} -> {:>10} {} {:08x} {}", entry.mode, entry.size, entry.compressed, utc(entry.mtime), entry.checksum, entry.path);
+    }
+    Ok(0)
+}
+
+/// Extracts the entries matching the paths, all of them without paths
+fn archive_extract(matches: &ArgMatches, mut options: huffman::Options, cancel: &huffman::CancelToken) -> Result<i32, Box<dyn Error>> {
+    let archive = archive::Archive::open(matches.value_of("archive").unwrap(), false)?;
+    let dir = Path::new(matches.value_of("directory").unwrap_or("."));
+    options.cancel = Some(cancel.clone());
+    let paths: Vec<&str> = matches.values_of("paths").map_or(Vec::new(), |paths| paths.map(|p| p.trim_end_matches('/')).collect());
+    // An entry is selected by its path or the path of one of its directories
+    let selected = |entry: &str, path: &str| entry == path || entry.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'));
+    for path in &paths {
+        if !archive.entries().iter().any(|entry| selected(&entry.path, path)) {
+            return Err(From::from(format!("[-] [{}]: Not in the archive.", path)));
+        }
+    }
+
+    let mut code = 0;
+    for entry in archive.entries() {
+        if !paths.is_empty() && !paths.iter().any(|path| selected(&entry.path, path)) {
+            continue;
+        }
+        let result = match dir.join(&entry.path).exists() && !matches.is_present("force") {
+            true => Err(From::from("Already exists, use -f.")),
+            false => archive.extract(entry, dir, &options),
+        };
+        match result {
+            Ok(path) => println!("[+] [{}]: [{}] bytes", path.display(), entry.size),
+            Err(e) if e.is::<huffman::Cancelled>() => return Err(e),
+            Err(e) => {
+                eprintln!("[-] [{}]: {}", entry.path, e.to_string().trim_start_matches("[-] "));
+                code = 1;
+            },
+        }
+    }
+    Ok(code)
+}
+
+/// Error message with the prefix of the library ones
+fn message(e: &dyn Error) -> String {
+    let message = e.to_string();
+    match message.starts_with("[-]") {
+        true => message,
+        false => format!("[-] {}", message),
+    }
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let (name, sub) = matches.subcommand();
+    let sub = sub.unwrap();
+    // Subcommands of archive, the one with the arguments
+    let (action, leaf) = match sub.subcommand() {
+        (action, Some(leaf)) => (action, leaf),
+        _ => ("", sub),
+    };
+
+    // Global arguments end up in the matches of the innermost subcommand
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(match leaf.occurrences_of("verbose") {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+    let json = leaf.value_of("format") == Some("json");
+
+    // Ctrl-C stops at the next block and removes the partial output
+    let cancel = huffman::CancelToken::new();
+    let token = cancel.clone();
+    if let Err(e) = ctrlc::set_handler(move || token.cancel()) {
+        log::warn!("Ctrl-C will not clean up: {}", e);
+    }
+
+    let result = options(leaf).and_then(|options| match (name, action) {
+        ("compress", _) => run(sub, true, options, &cancel, json),
+        ("decompress", _) => run(sub, false, options, &cancel, json),
+        ("test", _) => test(sub, &options, json),
+        ("list", _) => list(sub, &options, false, json),
+        ("dump-tree", _) => list(sub, &options, true, json),
+        ("bench", _) => bench(sub, options, &cancel, json),
+        ("stats", _) => {
+            let stats = huffman::stats(&String::from(sub.value_of("input").unwrap()))?;
+            match json {
+                true => println!("{}", stats.to_json()),
+                false => print!("{}", stats),
+            }
+            Ok(0)
+        },
+        ("train", _) => {
+            let paths_in: Vec<String> = sub.values_of("input").unwrap().map(String::from).collect();
+            huffman::train(&paths_in, &output_path(sub, ".hdict"))?;
+            Ok(0)
+        },
+        ("archive", "add") => archive_add(leaf, options, &cancel),
+        ("archive", "list") => archive_list(leaf, json),
+        ("archive", "extract") => archive_extract(leaf, options, &cancel),
+        _ => unreachable!(),
+    });
+    std::process::exit(match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", message(&*e));
+            match e.is::<huffman::Cancelled>() {
+                true => EXIT_CANCELLED,
+                false => EXIT_ERROR,
+            }
+        },
+    });
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_output_name() -> Result<(), Box<dyn Error>> {
+        let path = Path::new("dir/a.txt");
+        assert_eq!(output_name(path, true, SUFFIX, None)?, Path::new("dir/a.txt.huff"));
+        assert_eq!(output_name(Path::new("dir/a.txt.huff"), false, SUFFIX, None)?, path);
+        assert_eq!(output_name(Path::new("dir/a.txt.hz"), false, ".hz", None)?, path);
+        assert_eq!(output_name(Path::new("dir/a.txt.bz2"), false, SUFFIX, None)?, path);
+        // The stored name wins, without its directories
+        assert_eq!(output_name(Path::new("dir/b.huff"), false, SUFFIX, Some(String::from("../a.txt")))?, path);
+
+        assert!(output_name(Path::new("dir/a.txt.huff"), true, SUFFIX, None).is_err());
+        assert!(output_name(path, false, SUFFIX, None).is_err());
+        assert!(output_name(Path::new(".huff"), false, SUFFIX, None).is_err());
 
+        Ok(())
+    }
+
+    #[test]
+    fn check_test_status() -> Result<(), Box<dyn Error>> {
+        let status = |inputs: &[&str]| {
+            let matches = app().get_matches_from([&["huffman", "test"], inputs].concat());
+            test(matches.subcommand_matches("test").unwrap(), &huffman::Options::default(), true)
+        };
+        assert_eq!(status(&["./data/missing.huff"])?, EXIT_ERROR);
+        assert_eq!(status(&["./data/tara.txt"])?, EXIT_CORRUPT);
+        assert_eq!(status(&["./data/missing.huff", "./data/tara.txt"])?, EXIT_CORRUPT);
+        assert_eq!(status(&["./data/lazy_dog.txt.bz2"])?, 0);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,224 @@
+use std::error::Error;
+
+/// Probabilities are 11 bits fixed point numbers
+const PROB_BITS: u32 = 11;
+const PROB_INIT: u16 = 1 << (PROB_BITS - 1);
+/// Speed of adaptation: every bit moves the probability by 1/32 of its distance to the bit
+const MOVE_BITS: u32 = 5;
+/// The range is renormalized when its highest byte is 0
+const TOP: u32 = 1 << 24;
+
+/// Adaptive binary range encoder (LZMA style, carries propagated through the cached bytes)
+pub struct Encoder {
+    low: u64,
+    range: u32,
+    // Last byte not written yet and number of 0xFF bytes following it
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Encoder::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xff00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            loop {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xff;
+                self.cache_size -= 1;
+                if self.cache_size == 0 {
+                    break;
+                }
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    /// Encodes a bit whose probability of being 0 is `prob`, then adapts `prob`
+    pub fn encode_bit(&mut self, prob: &mut u16, bit: bool) {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        } else {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    /// Flushes the state and returns the coded bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+}
+
+/// Decoder of an `Encoder` stream, bytes past its end read as zeros
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        // The first byte is the initial empty cache of the encoder
+        if data.len() < 5 || data[0] != 0 {
+            return Err(From::from("[-] Invalid range coder stream."));
+        }
+        let mut decoder = Decoder { data, position: 1, range: u32::MAX, code: 0 };
+        for _ in 0..4 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte() as u32;
+        }
+        Ok(decoder)
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let ret = self.data.get(self.position).copied().unwrap_or(0);
+        self.position += 1;
+        ret
+    }
+
+    /// Decodes a bit whose probability of being 0 is `prob`, then adapts `prob`
+    pub fn decode_bit(&mut self, prob: &mut u16) -> bool {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+            *prob -= *prob >> MOVE_BITS;
+        } else {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+        bit
+    }
+
+    /// True when more bytes were read than the stream holds
+    pub fn overflowed(&self) -> bool {
+        self.position > self.data.len()
+    }
+}
+
+/// Order 0 adaptive model of bytes: a binary tree of probabilities, one per bit prefix
+pub struct ByteModel {
+    probs: [u16; 256],
+}
+
+impl Default for ByteModel {
+    fn default() -> Self {
+        ByteModel::new()
+    }
+}
+
+impl ByteModel {
+    pub fn new() -> Self {
+        ByteModel { probs: [PROB_INIT; 256] }
+    }
+
+    /// Encodes a byte, highest bit first
+    pub fn encode(&mut self, encoder: &mut Encoder, byte: u8) {
+        let mut node = 1usize;
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1 != 0;
+            encoder.encode_bit(&mut self.probs[node], bit);
+            node = (node << 1) | bit as usize;
+        }
+    }
+
+    /// Decodes a byte, highest bit first
+    pub fn decode(&mut self, decoder: &mut Decoder) -> u8 {
+        let mut node = 1usize;
+        while node < 256 {
+            node = (node << 1) | decoder.decode_bit(&mut self.probs[node]) as usize;
+        }
+        node as u8
+    }
+}
+
+/// Range codes the data with an adaptive order 0 model
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    let mut model = ByteModel::new();
+    for &b in data {
+        model.encode(&mut encoder, b);
+    }
+    encoder.finish()
+}
+
+/// Largest output of `compress` for `size` bytes: each of the 8 bits of a byte has a probability of
+/// at least 1 / 2^PROB_BITS and loses at most one more bit to rounding, then the flush adds 6 bytes
+pub fn max_compressed_size(size: usize) -> usize {
+    size * (PROB_BITS as usize + 1) + 6
+}
+
+/// Decodes `size` bytes written by `compress`
+pub fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decoder = Decoder::new(data)?;
+    let mut model = ByteModel::new();
+    let ret = (0..size).map(|_| model.decode(&mut decoder)).collect();
+    if decoder.overflowed() {
+        return Err(From::from("[-] Range coder stream too short."));
+    }
+    Ok(ret)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_round_trip() -> Result<(), Box<dyn Error>> {
+        let file_lazydog = std::fs::read("./data/lazy_dog.txt")?;
+        let all: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        let ones = vec![0xffu8; 100000];
+
+        for data in [&file_lazydog[..], &all, &ones, b""] {
+            let coded = compress(data);
+            assert!(coded.len() <= max_compressed_size(data.len()));
+            assert_eq!(decompress(&coded, data.len())?, data);
+        }
+        assert!(decompress(&compress(&file_lazydog)[..8], file_lazydog.len()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_skewed() {
+        // About 0.3 bit of entropy per byte, where Huffman needs 1 bit
+        let data: Vec<u8> = (0..65536u32).map(|i| if (i.wrapping_mul(2654435761) >> 24) < 13 { b'b' } else { b'a' }).collect();
+        assert!(compress(&data).len() < 65536 / 8 / 2);
+    }
+}
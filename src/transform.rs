@@ -0,0 +1,231 @@
+use std::error::Error;
+
+/// Zero runs are written as bijective base-2 digits, RUNA worth 1 and RUNB worth 2
+const RUNA: u8 = 0;
+const RUNB: u8 = 1;
+/// Followed by 0 or 1 for the two highest move-to-front positions, 254 and 255
+const ESCAPE: u8 = 255;
+
+/// Sorts the rotations of `data` by prefix doubling, returns their start positions
+fn sort_rotations(data: &[u8]) -> Vec<u32> {
+    let n = data.len();
+    let mut order: Vec<u32> = (0..n as u32).collect();
+    order.sort_by_key(|&i| data[i as usize]);
+    let mut rank = vec![0u32; n];
+    for i in 1..n {
+        let (a, b) = (order[i - 1] as usize, order[i] as usize);
+        rank[b] = rank[a] + (data[a] != data[b]) as u32;
+    }
+
+    let mut len = 1;
+    let mut shifted = vec![0u32; n];
+    let mut count = vec![0usize; n + 1];
+    let mut new_rank = vec![0u32; n];
+    while len < n && (rank[order[n - 1] as usize] as usize) < n - 1 {
+        // Ordered by the second half, the first half gets a stable counting sort
+        for (s, &o) in shifted.iter_mut().zip(order.iter()) {
+            *s = ((o as usize + n - len) % n) as u32;
+        }
+        count.iter_mut().for_each(|c| *c = 0);
+        for &s in &shifted {
+            count[rank[s as usize] as usize + 1] += 1;
+        }
+        for i in 1..=n {
+            count[i] += count[i - 1];
+        }
+        for &s in &shifted {
+            let r = rank[s as usize] as usize;
+            order[count[r]] = s;
+            count[r] += 1;
+        }
+
+        new_rank[order[0] as usize] = 0;
+        for i in 1..n {
+            let (a, b) = (order[i - 1] as usize, order[i] as usize);
+            let differ = rank[a] != rank[b] || rank[(a + len) % n] != rank[(b + len) % n];
+            new_rank[b] = new_rank[a] + differ as u32;
+        }
+        std::mem::swap(&mut rank, &mut new_rank);
+        len <<= 1;
+    }
+
+    order
+}
+
+/// Burrows-Wheeler transform: last column of the sorted rotations and the row of `data` itself
+pub fn bwt(data: &[u8]) -> (Vec<u8>, usize) {
+    let n = data.len();
+    let order = sort_rotations(data);
+    let mut origin = 0;
+    let last = order.iter()
+        .enumerate()
+        .map(|(row, &start)| {
+            if start == 0 {
+                origin = row;
+            }
+            data[(start as usize + n - 1) % n]
+        })
+        .collect();
+    (last, origin)
+}
+
+/// Rebuilds the data from its Burrows-Wheeler transform
+pub fn inverse_bwt(last: &[u8], origin: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if last.is_empty() {
+        return Ok(Vec::new());
+    }
+    if origin >= last.len() {
+        return Err(From::from("[-] Invalid BWT origin."));
+    }
+
+    // First position of every byte in the sorted column
+    let mut start = [0usize; 256];
+    for &b in last {
+        start[b as usize] += 1;
+    }
+    let mut sum = 0;
+    for s in start.iter_mut() {
+        let count = *s;
+        *s = sum;
+        sum += count;
+    }
+
+    // next[i]: row holding the rotation that follows row i
+    let mut next = vec![0u32; last.len()];
+    for (i, &b) in last.iter().enumerate() {
+        next[start[b as usize]] = i as u32;
+        start[b as usize] += 1;
+    }
+
+    let mut ret = Vec::with_capacity(last.len());
+    let mut position = next[origin] as usize;
+    for _ in 0..last.len() {
+        ret.push(last[position]);
+        position = next[position] as usize;
+    }
+    Ok(ret)
+}
+
+/// Writes a run of `n` move-to-front zeros as RUNA/RUNB digits, least significant first
+fn push_run(out: &mut Vec<u8>, mut n: usize) {
+    while n > 0 {
+        n -= 1;
+        out.push(if n & 1 == 0 { RUNA } else { RUNB });
+        n >>= 1;
+    }
+}
+
+/// Move-to-front coding followed by zero run length coding
+pub fn mtf_rle(data: &[u8]) -> Vec<u8> {
+    let mut table: Vec<u8> = (0..=255).collect();
+    let mut ret = Vec::with_capacity(data.len());
+    let mut run = 0;
+    for &b in data {
+        let position = table.iter().position(|&t| t == b).unwrap();
+        if position == 0 {
+            run += 1;
+            continue;
+        }
+        push_run(&mut ret, run);
+        run = 0;
+        table.remove(position);
+        table.insert(0, b);
+        // Positions 1 to 253 are shifted by one to make room for RUNA/RUNB
+        match position {
+            1..=253 => ret.push(position as u8 + 1),
+            _ => ret.extend_from_slice(&[ESCAPE, position as u8 - 254]),
+        }
+    }
+    push_run(&mut ret, run);
+    ret
+}
+
+/// Undoes `mtf_rle`, refusing data that decodes to more than `size` bytes
+pub fn unmtf_rle(data: &[u8], size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut table: Vec<u8> = (0..=255).collect();
+    let mut ret = Vec::with_capacity(data.len().min(size));
+    let mut run = 0usize;
+    let mut digit = 1usize;
+    let mut symbols = data.iter();
+    while let Some(&symbol) = symbols.next() {
+        if symbol == RUNA || symbol == RUNB {
+            run += digit << symbol;
+            digit <<= 1;
+            if run > size - ret.len() {
+                return Err(From::from("[-] Zero run past the end of the block."));
+            }
+            continue;
+        }
+        if run + 1 > size - ret.len() {
+            return Err(From::from("[-] Move-to-front data past the end of the block."));
+        }
+        ret.extend(std::iter::repeat_n(table[0], run));
+        run = 0;
+        digit = 1;
+
+        let position = match symbol {
+            ESCAPE => match symbols.next() {
+                Some(&extra) if extra < 2 => 254 + extra as usize,
+                _ => return Err(From::from("[-] Invalid move-to-front escape.")),
+            },
+            s => s as usize - 1,
+        };
+        let b = table.remove(position);
+        table.insert(0, b);
+        ret.push(b);
+    }
+    ret.extend(std::iter::repeat_n(table[0], run));
+    Ok(ret)
+}
+
+/// BWT, move-to-front and zero run length coding of a block, returns the coded block and the BWT origin
+pub fn encode(block: &[u8]) -> (Vec<u8>, usize) {
+    let (last, origin) = bwt(block);
+    (mtf_rle(&last), origin)
+}
+
+/// Undoes `encode`, `size` is the size of the block
+pub fn decode(data: &[u8], origin: usize, size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    inverse_bwt(&unmtf_rle(data, size)?, origin)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_bwt() -> Result<(), Box<dyn Error>> {
+        let (last, origin) = bwt(b"banana");
+        assert_eq!((&last[..], origin), (&b"nnbaaa"[..], 3));
+        assert_eq!(inverse_bwt(&last, origin)?, b"banana");
+
+        // Identical rotations
+        let (last, origin) = bwt(b"abababab");
+        assert_eq!(inverse_bwt(&last, origin)?, b"abababab");
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_round_trip() -> Result<(), Box<dyn Error>> {
+        let file_lazydog = std::fs::read("./data/lazy_dog.txt")?;
+        let runs: Vec<u8> = (0..20000u32).map(|i| (i / 1000) as u8).collect();
+        let all: Vec<u8> = (0..5000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+
+        for data in [&file_lazydog[..], &runs, &all, b"a", b""] {
+            let (coded, origin) = encode(data);
+            assert_eq!(decode(&coded, origin, data.len())?, data);
+        }
+
+        // The long runs collapse to a few symbols
+        assert!(encode(&runs).0.len() < 300);
+
+        // Runs are not expanded past the size of the block
+        let (coded, origin) = encode(&runs);
+        assert!(decode(&coded, origin, runs.len() - 1).is_err());
+        assert!(unmtf_rle(&[RUNB; 64], 1 << 20).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use crate::code::HuffmanCode;
+use crate::file_bin::{BitRead, BitWrite};
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+/// Bytes that are not part of valid UTF-8 are coded as ESCAPE + byte, past the last code point
+const ESCAPE: u32 = 0x11_0000;
+/// Largest alphabet, data with more distinct symbols is better coded byte by byte
+pub const MAX_ALPHABET: usize = 1 << 16;
+
+/// Unicode scalar values of the data, invalid bytes escaped
+pub fn to_symbols(data: &[u8]) -> Vec<u32> {
+    let mut ret = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(_) => (rest.len(), 0),
+            // Sequences cut by the end of the data are escaped too
+            Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(rest.len() - e.valid_up_to())),
+        };
+        let text = std::str::from_utf8(&rest[..valid]).unwrap();
+        ret.extend(text.chars().map(|c| c as u32));
+        ret.extend(rest[valid..valid + invalid].iter().map(|&b| ESCAPE + b as u32));
+        rest = &rest[valid + invalid..];
+    }
+    ret
+}
+
+/// Undoes `to_symbols`
+pub fn from_symbols(symbols: &[u32]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut ret = Vec::with_capacity(symbols.len());
+    let mut buffer = [0u8; 4];
+    for &symbol in symbols {
+        if symbol >= ESCAPE {
+            ret.push(u8::try_from(symbol - ESCAPE).map_err(|_| "[-] Invalid escaped byte.")?);
+        } else {
+            let c = char::from_u32(symbol).ok_or("[-] Invalid code point.")?;
+            ret.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+        }
+    }
+    Ok(ret)
+}
+
+/// Writes a LEB128 number, 7 bits per byte
+fn write_varint<W: BitWrite>(writer: &mut W, mut value: u32) -> Result<(), Box<dyn Error>> {
+    while value >= 0x80 {
+        writer.write_bits((value & 0x7f) | 0x80, 8)?;
+        value >>= 7;
+    }
+    writer.write_bits(value, 8)
+}
+
+fn read_varint<R: BitRead>(reader: &mut R) -> Result<u32, Box<dyn Error>> {
+    let mut ret = 0u32;
+    for shift in (0..32).step_by(7) {
+        let byte = reader.read_bits(8)?;
+        ret |= (byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(ret);
+        }
+    }
+    Err(From::from("[-] Invalid varint."))
+}
+
+/// Writes the data as Huffman coded scalar values: alphabet, symbol count and codes.
+/// The alphabet is sorted and stored as varint gaps between code points, then the code lengths.
+/// Returns false without writing anything when the alphabet is over `MAX_ALPHABET`.
+pub fn encode<W: BitWrite>(data: &[u8], writer: &mut W) -> Result<bool, Box<dyn Error>> {
+    let symbols = to_symbols(data);
+    let code = HuffmanCode::from_weights(symbols.iter().map(|&s| (s, 1)));
+    if code.symbols().len() > MAX_ALPHABET {
+        return Ok(false);
+    }
+
+    write_varint(writer, code.symbols().len() as u32)?;
+    let mut previous = 0;
+    for &symbol in code.symbols() {
+        write_varint(writer, symbol - previous)?;
+        previous = symbol;
+    }
+    for len in code.lengths() {
+        writer.write_bits(len as u32, 8)?;
+    }
+
+    write_varint(writer, symbols.len() as u32)?;
+    code.encode(symbols, writer)?;
+    Ok(true)
+}
+
+/// Reads scalar values written by `encode`, `size` is the size of the decoded data
+pub fn decode<R: BitRead>(reader: &mut R, size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let nb = read_varint(reader)? as usize;
+    if nb > size || nb > MAX_ALPHABET {
+        return Err(From::from("[-] Alphabet bigger than the data or the limit."));
+    }
+    let mut symbols = Vec::with_capacity(nb);
+    let mut previous = 0u32;
+    for _ in 0..nb {
+        previous = previous.checked_add(read_varint(reader)?).ok_or("[-] Invalid alphabet.")?;
+        symbols.push(previous);
+    }
+    let lengths = (0..nb).map(|_| reader.read_bits(8).map(|l| l as u8)).collect::<Result<Vec<u8>, _>>()?;
+    let code = HuffmanCode::from_lengths(symbols, &lengths)?;
+
+    let count = read_varint(reader)? as usize;
+    if count > size {
+        return Err(From::from("[-] More symbols than bytes."));
+    }
+    let symbols: Vec<u32> = code.decode(reader).take(count).collect::<Result<_, _>>()?;
+    let ret = from_symbols(&symbols)?;
+    if ret.len() != size {
+        return Err(From::from("[-] Symbol stream size mismatch."));
+    }
+    Ok(ret)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_symbols() -> Result<(), Box<dyn Error>> {
+        let text = "tara ταρα タラ 🦀";
+        let symbols = to_symbols(text.as_bytes());
+        assert_eq!(symbols.len(), text.chars().count());
+        assert_eq!(from_symbols(&symbols)?, text.as_bytes());
+
+        // Stray continuation byte, overlong encoding and a character cut by the end
+        let data = [b'a', 0x80, b'b', 0xc0, 0xaf, 0xe3, 0x82];
+        let symbols = to_symbols(&data);
+        assert_eq!(symbols, vec![0x61, ESCAPE + 0x80, 0x62, ESCAPE + 0xc0, ESCAPE + 0xaf, ESCAPE + 0xe3, ESCAPE + 0x82]);
+        assert_eq!(from_symbols(&symbols)?, data);
+
+        assert!(from_symbols(&[0xd800]).is_err());
+
+        Ok(())
+    }
+}
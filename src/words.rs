@@ -0,0 +1,151 @@
+use crate::code::HuffmanCode;
+use crate::file_bin::{BitRead, BitWrite};
+
+use std::error::Error;
+
+/// Longest token, longer runs are split. A word this long is never followed by an implicit space.
+const MAX_TOKEN_LEN: usize = 255;
+/// Largest vocabulary, data with more distinct tokens is better coded byte by byte
+pub const MAX_VOCABULARY: usize = 1 << 16;
+
+/// Letters, digits and every byte of multibyte UTF-8 characters make words
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b >= 0x80
+}
+
+fn is_word(token: &[u8]) -> bool {
+    is_word_byte(token[0])
+}
+
+/// Splits the data into alternating words and separators (spaceless word model): the single
+/// space between two words is implied and not part of the tokens
+pub fn tokenize(data: &[u8]) -> Vec<&[u8]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=data.len() {
+        if i == data.len() || i - start == MAX_TOKEN_LEN || is_word_byte(data[i]) != is_word_byte(data[start]) {
+            runs.push(&data[start..i]);
+            start = i;
+        }
+    }
+
+    let mut ret = Vec::with_capacity(runs.len());
+    for (i, &run) in runs.iter().enumerate() {
+        let implied = run == b" "
+            && i > 0
+            && i + 1 < runs.len()
+            && is_word(runs[i - 1])
+            && runs[i - 1].len() < MAX_TOKEN_LEN
+            && is_word(runs[i + 1]);
+        if !implied {
+            ret.push(run);
+        }
+    }
+    ret
+}
+
+/// Joins tokens back, putting the implied spaces between words
+pub fn detokenize<T: AsRef<[u8]>>(tokens: &[T]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut previous: Option<&[u8]> = None;
+    for token in tokens {
+        let token = token.as_ref();
+        if let Some(previous) = previous {
+            if is_word(previous) && previous.len() < MAX_TOKEN_LEN && is_word(token) {
+                ret.push(b' ');
+            }
+        }
+        ret.extend_from_slice(token);
+        previous = Some(token);
+    }
+    ret
+}
+
+/// Writes the data as a Huffman coded token stream: vocabulary, token count and codes.
+/// The vocabulary is sorted and front coded: length of the prefix shared with the previous
+/// token, length and bytes of the rest, then the code length.
+/// Returns false without writing anything when the vocabulary is over `MAX_VOCABULARY`.
+pub fn encode<W: BitWrite>(data: &[u8], writer: &mut W) -> Result<bool, Box<dyn Error>> {
+    let tokens = tokenize(data);
+    let code = HuffmanCode::from_weights(tokens.iter().map(|&t| (t, 1)));
+    if code.symbols().len() > MAX_VOCABULARY {
+        return Ok(false);
+    }
+
+    writer.write_bits(code.symbols().len() as u32, 32)?;
+    let mut previous: &[u8] = &[];
+    for (&token, len) in code.symbols().iter().zip(code.lengths()) {
+        let shared = previous.iter().zip(token.iter()).take_while(|(a, b)| a == b).count();
+        writer.write_bits(shared as u32, 8)?;
+        writer.write_bits((token.len() - shared) as u32, 8)?;
+        for &b in &token[shared..] {
+            writer.write_bits(b as u32, 8)?;
+        }
+        writer.write_bits(len as u32, 8)?;
+        previous = token;
+    }
+
+    writer.write_bits(tokens.len() as u32, 32)?;
+    code.encode(tokens, writer)?;
+    Ok(true)
+}
+
+/// Reads a token stream written by `encode`, `size` is the size of the decoded data
+pub fn decode<R: BitRead>(reader: &mut R, size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let nb = reader.read_bits(32)? as usize;
+    if nb > size || nb > MAX_VOCABULARY {
+        return Err(From::from("[-] Vocabulary bigger than the data or the limit."));
+    }
+    let mut symbols: Vec<Vec<u8>> = Vec::with_capacity(nb);
+    let mut lengths = Vec::with_capacity(nb);
+    for _ in 0..nb {
+        let shared = reader.read_bits(8)? as usize;
+        let mut token = match symbols.last() {
+            Some(previous) if shared <= previous.len() => previous[..shared].to_vec(),
+            None if shared == 0 => Vec::new(),
+            _ => return Err(From::from("[-] Invalid vocabulary prefix.")),
+        };
+        for _ in 0..reader.read_bits(8)? {
+            token.push(reader.read_bits(8)? as u8);
+        }
+        if token.is_empty() {
+            return Err(From::from("[-] Empty token in vocabulary."));
+        }
+        symbols.push(token);
+        lengths.push(reader.read_bits(8)? as u8);
+    }
+    let code = HuffmanCode::from_lengths(symbols, &lengths)?;
+
+    let count = reader.read_bits(32)? as usize;
+    if count > size {
+        return Err(From::from("[-] More tokens than bytes."));
+    }
+    let tokens: Vec<Vec<u8>> = code.decode(reader).take(count).collect::<Result<_, _>>()?;
+    let ret = detokenize(&tokens);
+    if ret.len() != size {
+        return Err(From::from("[-] Token stream size mismatch."));
+    }
+    Ok(ret)
+}
+
+/// TESTS
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_tokenize() {
+        let data = b"The quick  fox, the dog.\n";
+        let tokens = tokenize(data);
+        let expected: Vec<&[u8]> = vec![b"The", b"quick", b"  ", b"fox", b", ", b"the", b"dog", b".\n"];
+        assert_eq!(tokens, expected);
+        assert_eq!(detokenize(&tokens), data);
+
+        // Words over the token limit are split without an implied space
+        let mut data = vec![b'z'; 600];
+        data.extend_from_slice(b" end ");
+        let tokens = tokenize(&data);
+        assert_eq!(tokens.iter().map(|t| t.len()).collect::<Vec<_>>(), vec![255, 255, 90, 3, 1]);
+        assert_eq!(detokenize(&tokens), data);
+    }
+}